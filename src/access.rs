@@ -0,0 +1,100 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// File under each root that the access log is persisted to.
+const ACCESS_LOG_FILE: &str = ".access-log";
+
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    pub last_access: SystemTime,
+    pub hits: u64,
+}
+
+/// Records when each key was last read, independent of filesystem atime
+/// (which is commonly disabled with `noatime`/`relatime`).
+///
+/// Kept in memory and written to `<root>/.access-log` by [`AccessLog::save`].
+pub struct AccessLog {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, Access>>,
+}
+
+impl AccessLog {
+    /// Loads the access log for `root`, starting empty if there is none yet.
+    pub fn load(root: &Path) -> io::Result<AccessLog> {
+        let path = root.join(ACCESS_LOG_FILE);
+
+        let mut entries = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let mut fields = line.split_whitespace();
+                    let (Some(key), Some(secs), Some(hits)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        continue;
+                    };
+                    let (Ok(secs), Ok(hits)) = (secs.parse(), hits.parse()) else {
+                        continue;
+                    };
+
+                    entries.insert(
+                        key.to_owned(),
+                        Access {
+                            last_access: UNIX_EPOCH + Duration::from_secs(secs),
+                            hits,
+                        },
+                    );
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(AccessLog {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Marks `key` as read just now.
+    pub fn record(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key.to_owned()).or_insert(Access {
+            last_access: SystemTime::now(),
+            hits: 0,
+        });
+        entry.last_access = SystemTime::now();
+        entry.hits += 1;
+    }
+
+    pub fn get(&self, key: &str) -> Option<Access> {
+        self.entries.lock().unwrap().get(key).copied()
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    /// Writes the log to disk, replacing the previous copy atomically.
+    pub fn save(&self) -> io::Result<()> {
+        let mut contents = String::new();
+        for (key, access) in self.entries.lock().unwrap().iter() {
+            let secs = access
+                .last_access
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            contents += &format!("{} {} {}\n", key, secs, access.hits);
+        }
+
+        let staging = self.path.with_extension("tmp");
+        fs::write(&staging, contents)?;
+        fs::rename(&staging, &self.path)
+    }
+}
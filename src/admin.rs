@@ -0,0 +1,383 @@
+//! Maintenance commands that work on the cache storage directly, without a
+//! running server.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::StreamExt;
+
+use crate::{
+    access::AccessLog,
+    config::Config,
+    eviction, package,
+    pins::Pins,
+    retention,
+    storage::{open_local, ContentHasher, FsStorage, Layout, Storage},
+    validate_key,
+};
+
+/// Staging files at least this old are assumed abandoned by `gc`, even if a
+/// server is running on the same root.
+const GC_STAGING_MIN_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The binary and asset storage of one cache.
+pub struct Caches {
+    pub binary: Arc<dyn Storage>,
+    pub asset: Arc<dyn Storage>,
+}
+
+impl Caches {
+    fn both(&self) -> [(&'static str, Layout, &dyn Storage); 2] {
+        [
+            ("binary", Layout::Binary, self.binary.as_ref()),
+            ("asset", Layout::Asset, self.asset.as_ref()),
+        ]
+    }
+}
+
+/// Only assets are named after the digest of their content.
+fn expected_digest(layout: Layout, key: &str) -> Option<&str> {
+    match layout {
+        Layout::Binary => None,
+        Layout::Asset => Some(key),
+    }
+}
+
+/// Prints entry counts and sizes, per prefix directory for binary packages.
+pub async fn stats(caches: &Caches) -> anyhow::Result<()> {
+    for (name, layout, storage) in caches.both() {
+        let entries = storage
+            .list()
+            .await
+            .with_context(|| format!("listing {} cache", name))?;
+
+        let total: u64 = entries.iter().map(|e| e.size).sum();
+        println!(
+            "{}: {} entries, {}",
+            name,
+            entries.len(),
+            human_bytes::human_bytes(total as f64)
+        );
+
+        if let Layout::Binary = layout {
+            let mut prefixes: BTreeMap<&str, (usize, u64)> = BTreeMap::new();
+            for entry in &entries {
+                let prefix = prefixes
+                    .entry(entry.key.get(..2).unwrap_or(&entry.key))
+                    .or_default();
+                prefix.0 += 1;
+                prefix.1 += entry.size;
+            }
+            for (prefix, (count, size)) in prefixes {
+                println!(
+                    "  {}: {} entries, {}",
+                    prefix,
+                    count,
+                    human_bytes::human_bytes(size as f64)
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the package layout and reads every member, which checks each CRC
+/// along the way.
+fn check_package(file: File) -> anyhow::Result<()> {
+    let mut archive = package::open(file)?;
+    for i in 0..archive.len() {
+        io::copy(&mut archive.by_index(i)?, &mut io::sink())?;
+    }
+    Ok(())
+}
+
+fn check_digest(mut file: File, key: &str) -> io::Result<()> {
+    let mut hasher = ContentHasher::for_key(key);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    hasher.verify(key)
+}
+
+/// Checks that every binary package is a well-formed vcpkg package and that every asset
+/// matches its digest. Returns the number of corrupt entries.
+pub async fn verify(caches: &Caches) -> anyhow::Result<usize> {
+    let mut corrupt = 0;
+
+    for (name, layout, storage) in caches.both() {
+        let entries = storage
+            .list()
+            .await
+            .with_context(|| format!("listing {} cache", name))?;
+
+        for entry in entries {
+            let Some(file) = open_local(storage, &entry.key)
+                .await
+                .with_context(|| format!("reading {} {}", name, entry.key))?
+            else {
+                // removed since it was listed
+                continue;
+            };
+
+            let result = tokio::task::block_in_place(|| match layout {
+                Layout::Binary => check_package(file).map_err(|e| e.to_string()),
+                Layout::Asset => check_digest(file, &entry.key).map_err(|e| e.to_string()),
+            });
+            if let Err(e) = result {
+                println!("corrupt {} {}: {}", name, entry.key, e);
+                corrupt += 1;
+            }
+        }
+    }
+
+    Ok(corrupt)
+}
+
+/// Removes abandoned staging files, expires entries according to the
+/// configured retention and evicts entries beyond the size limits.
+pub async fn gc(config: &Config, caches: &Caches, pins: &Pins) -> anyhow::Result<()> {
+    if config.s3_bucket.is_none() {
+        for (root, layout) in [
+            (&config.binary_root, Layout::Binary),
+            (&config.asset_root, Layout::Asset),
+        ] {
+            FsStorage::new(root.clone(), layout)
+                .sweep_staging_files(GC_STAGING_MIN_AGE)
+                .with_context(|| format!("removing staging files in {}", root.display()))?;
+        }
+    }
+
+    for ((name, _, storage), (root, limits, pins)) in caches.both().into_iter().zip([
+        (&config.binary_root, config.binary_limits(), Some(pins)),
+        (&config.asset_root, config.asset_limits(), None),
+    ]) {
+        if !limits.is_set() {
+            continue;
+        }
+
+        let access_log = AccessLog::load(root)?;
+        let entries = storage
+            .list()
+            .await
+            .with_context(|| format!("listing {} cache", name))?;
+
+        let expiry = retention::expire(storage, entries, &limits.retention, &access_log, pins)
+            .await
+            .with_context(|| format!("expiring from {} cache", name))?;
+        let mut remaining = expiry.remaining;
+        let expired = expiry.expired.len();
+
+        let mut evicted = 0;
+        if let Some(max_size) = limits.max_size {
+            let before = remaining.len();
+            remaining = eviction::evict(storage, remaining, max_size, &access_log, pins)
+                .await
+                .with_context(|| format!("evicting from {} cache", name))?;
+            evicted = before - remaining.len();
+        }
+        access_log.save()?;
+
+        println!(
+            "{}: expired {} entries, evicted {}, {} remain",
+            name,
+            expired,
+            evicted,
+            remaining.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn is_tarball(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+fn is_gzip(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".gz") || name.ends_with(".tgz")
+}
+
+/// Copies every entry of `from` into `to`, skipping those `to` already has
+/// unless `overwrite` is set. Returns the number of entries copied.
+async fn copy_entries(
+    from: &dyn Storage,
+    to: &dyn Storage,
+    layout: Layout,
+    overwrite: bool,
+) -> io::Result<usize> {
+    let mut copied = 0;
+    for entry in from.list().await? {
+        if validate_key(&entry.key).is_err() {
+            continue;
+        }
+        if !overwrite && to.head(&entry.key).await?.is_some() {
+            continue;
+        }
+        let Some(stream) = from.get(&entry.key).await? else {
+            continue;
+        };
+
+        to.put(
+            &entry.key,
+            stream,
+            expected_digest(layout, &entry.key),
+            None,
+        )
+        .await?;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+/// Directories and tarballs contain `binary/` and `asset/` in the same layout
+/// as the roots.
+fn dir_storage(dir: &Path, name: &str, layout: Layout) -> FsStorage {
+    FsStorage::new(dir.join(name), layout)
+}
+
+/// Imports entries from a directory or a `.tar`/`.tar.gz` made by [`export`].
+pub async fn import(caches: &Caches, from: &Path, overwrite: bool) -> anyhow::Result<()> {
+    if from.is_dir() {
+        for (name, layout, storage) in caches.both() {
+            let copied = copy_entries(&dir_storage(from, name, layout), storage, layout, overwrite)
+                .await
+                .with_context(|| format!("importing {} entries", name))?;
+            println!("{}: imported {} entries", name, copied);
+        }
+        return Ok(());
+    }
+
+    if !is_tarball(from) {
+        bail!(
+            "{} is neither a directory nor a .tar, .tar.gz or .tgz file",
+            from.display()
+        );
+    }
+
+    let file = File::open(from).with_context(|| format!("opening {}", from.display()))?;
+    let reader: Box<dyn Read> = if is_gzip(from) {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut archive = tar::Archive::new(reader);
+
+    let mut imported = 0;
+    for tar_entry in archive.entries()? {
+        let mut tar_entry = tar_entry?;
+        let path = tar_entry.path()?.into_owned();
+
+        let (layout, storage) = match path.components().next() {
+            Some(c) if c.as_os_str() == "binary" => (Layout::Binary, caches.binary.as_ref()),
+            Some(c) if c.as_os_str() == "asset" => (Layout::Asset, caches.asset.as_ref()),
+            _ => continue,
+        };
+        let Some(key) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| layout.key_from_file_name(name))
+            .filter(|key| validate_key(key).is_ok())
+            .map(str::to_owned)
+        else {
+            continue;
+        };
+
+        if !overwrite && storage.head(&key).await?.is_some() {
+            continue;
+        }
+
+        // tar entries can only be read in order, so buffer each one
+        let mut temp = tempfile::tempfile()?;
+        io::copy(&mut tar_entry, &mut temp)?;
+        temp.rewind()?;
+
+        let stream = tokio_util::io::ReaderStream::new(tokio::fs::File::from_std(temp)).boxed();
+        storage
+            .put(&key, stream, expected_digest(layout, &key), None)
+            .await
+            .with_context(|| format!("importing {}", path.display()))?;
+        imported += 1;
+    }
+    println!("imported {} entries", imported);
+
+    Ok(())
+}
+
+/// Exports every entry to a directory, or to a tarball if `to` ends in `.tar`,
+/// `.tar.gz` or `.tgz`.
+pub async fn export(caches: &Caches, to: &Path) -> anyhow::Result<()> {
+    if !is_tarball(to) {
+        for (name, layout, storage) in caches.both() {
+            let copied = copy_entries(storage, &dir_storage(to, name, layout), layout, false)
+                .await
+                .with_context(|| format!("exporting {} entries", name))?;
+            println!("{}: exported {} entries", name, copied);
+        }
+        return Ok(());
+    }
+
+    let file = File::create(to).with_context(|| format!("creating {}", to.display()))?;
+    if is_gzip(to) {
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        export_tar(caches, &mut builder).await?;
+        builder.into_inner()?.finish()?.sync_all()?;
+    } else {
+        let mut builder = tar::Builder::new(file);
+        export_tar(caches, &mut builder).await?;
+        builder.into_inner()?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+async fn export_tar<W: Write>(
+    caches: &Caches,
+    builder: &mut tar::Builder<W>,
+) -> anyhow::Result<()> {
+    for (name, layout, storage) in caches.both() {
+        let entries = storage
+            .list()
+            .await
+            .with_context(|| format!("listing {} cache", name))?;
+
+        let mut exported = 0;
+        for entry in entries {
+            let Some(file) = open_local(storage, &entry.key).await? else {
+                continue;
+            };
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(file.metadata()?.len());
+            header.set_mode(0o644);
+            header.set_mtime(
+                entry
+                    .modified
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            );
+            let path: PathBuf = [name, &layout.relative_path(&entry.key)].iter().collect();
+            tokio::task::block_in_place(|| builder.append_data(&mut header, &path, file))?;
+            exported += 1;
+        }
+        println!("{}: exported {} entries", name, exported);
+    }
+
+    Ok(())
+}
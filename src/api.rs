@@ -0,0 +1,353 @@
+//! Administrative endpoints for listing and removing cache entries.
+//!
+//! Everything goes through the same [`Storage`](crate::storage::Storage) as
+//! the data routes, so both always agree on where an entry lives. Deleting,
+//! purging, pinning and unpinning need an `admin` token.
+
+use std::{
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    namespace::{Cache, CurrentNamespace, Namespace},
+    not_found,
+    quota::Usage,
+    storage_error, validate_key, HashPath,
+};
+
+const DEFAULT_PAGE_SIZE: usize = 1000;
+const MAX_PAGE_SIZE: usize = 10_000;
+
+fn is_pinned(namespace: &Namespace, cache: Cache, hash: &str) -> bool {
+    namespace
+        .pins(cache)
+        .is_some_and(|pins| pins.contains(hash))
+}
+
+/// Catches up with pins other servers sharing the storage made, before
+/// deciding what may be deleted.
+async fn refresh_pins(namespace: &Namespace, cache: Cache) -> Result<(), (StatusCode, String)> {
+    match namespace.pins(cache) {
+        Some(pins) => pins.refresh().await.map_err(storage_error),
+        None => Ok(()),
+    }
+}
+
+/// Deletes `hash` along with what the access log and index know about it.
+async fn delete_entry(namespace: &Namespace, cache: Cache, hash: &str) -> io::Result<bool> {
+    let storage = namespace.storage(cache);
+    // looked up first so the quota is freed right away
+    let Some(meta) = storage.head(hash).await? else {
+        return Ok(false);
+    };
+    if !storage.delete(hash).await? {
+        return Ok(false);
+    }
+
+    namespace.quota.remove_stored(cache, meta.size);
+    namespace.access_log(cache).remove(hash);
+    if let Cache::Binary = cache {
+        if let Err(e) = tokio::task::block_in_place(|| namespace.index.remove(hash)) {
+            warn!("Failed to remove {} from the index: {}", hash, e);
+        }
+    }
+
+    info!(
+        "Deleted {} from {} cache in {}",
+        hash,
+        cache.name(),
+        namespace.name
+    );
+    Ok(true)
+}
+
+async fn delete(
+    namespace: &Namespace,
+    cache: Cache,
+    hash: &str,
+) -> Result<StatusCode, (StatusCode, String)> {
+    validate_key(hash)?;
+    refresh_pins(namespace, cache).await?;
+    if is_pinned(namespace, cache, hash) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("{} is pinned, unpin it first", hash),
+        ));
+    }
+
+    if !delete_entry(namespace, cache, hash)
+        .await
+        .map_err(storage_error)?
+    {
+        return Err(not_found(hash));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn cache_delete(
+    CurrentNamespace(namespace): CurrentNamespace,
+    Path(HashPath { hash }): Path<HashPath>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    delete(&namespace, Cache::Binary, &hash).await
+}
+
+pub async fn asset_delete(
+    CurrentNamespace(namespace): CurrentNamespace,
+    Path(HashPath { hash }): Path<HashPath>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    delete(&namespace, Cache::Asset, &hash).await
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// Only list hashes sorting after this one, i.e. the previous page's `next`
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct ListedEntry {
+    hash: String,
+    size: u64,
+    /// Seconds since the Unix epoch
+    mtime: u64,
+    pinned: bool,
+}
+
+#[derive(Serialize)]
+pub struct Page {
+    entries: Vec<ListedEntry>,
+    /// Pass as `after` to get the next page; absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
+async fn list(
+    namespace: &Namespace,
+    cache: Cache,
+    query: ListQuery,
+) -> Result<Json<Page>, (StatusCode, String)> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let storage = namespace.storage(cache);
+    let mut objects = storage.list().await.map_err(storage_error)?;
+    if let Some(after) = &query.after {
+        objects.retain(|object| object.key > *after);
+    }
+    objects.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+
+    let next = (objects.len() > limit).then(|| objects[limit - 1].key.clone());
+    objects.truncate(limit);
+
+    Ok(Json(Page {
+        entries: objects
+            .into_iter()
+            .map(|object| ListedEntry {
+                mtime: object
+                    .modified
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                size: object.size,
+                pinned: is_pinned(namespace, cache, &object.key),
+                hash: object.key,
+            })
+            .collect(),
+        next,
+    }))
+}
+
+/// Lists binary packages sorted by hash, `limit` at a time.
+pub async fn cache_list(
+    CurrentNamespace(namespace): CurrentNamespace,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Page>, (StatusCode, String)> {
+    list(&namespace, Cache::Binary, query).await
+}
+
+/// Lists assets sorted by hash, `limit` at a time.
+pub async fn asset_list(
+    CurrentNamespace(namespace): CurrentNamespace,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Page>, (StatusCode, String)> {
+    list(&namespace, Cache::Asset, query).await
+}
+
+#[derive(Deserialize)]
+pub struct PurgeQuery {
+    /// Only purge entries uploaded at least this many seconds ago
+    older_than: Option<u64>,
+    /// Only purge entries whose hash starts with this
+    prefix: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Purged {
+    deleted: usize,
+    bytes: u64,
+}
+
+async fn purge(
+    namespace: &Namespace,
+    cache: Cache,
+    query: PurgeQuery,
+) -> Result<Json<Purged>, (StatusCode, String)> {
+    if query.older_than.is_none() && query.prefix.is_none() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "at least one of older_than and prefix is required".to_owned(),
+        ));
+    }
+    if let Some(prefix) = &query.prefix {
+        if prefix.is_empty()
+            || !prefix
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "prefix must be lowercase hex".to_owned(),
+            ));
+        }
+    }
+    let cutoff = match query.older_than {
+        Some(secs) => Some(
+            SystemTime::now()
+                .checked_sub(Duration::from_secs(secs))
+                .ok_or_else(|| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        format!("older_than={} reaches back too far", secs),
+                    )
+                })?,
+        ),
+        None => None,
+    };
+
+    refresh_pins(namespace, cache).await?;
+    let storage = namespace.storage(cache);
+    let objects = storage.list().await.map_err(storage_error)?;
+
+    let mut purged = Purged {
+        deleted: 0,
+        bytes: 0,
+    };
+    for object in objects {
+        if is_pinned(namespace, cache, &object.key) {
+            continue;
+        }
+        if let Some(prefix) = &query.prefix {
+            if !object.key.starts_with(prefix.as_str()) {
+                continue;
+            }
+        }
+        if let Some(cutoff) = cutoff {
+            if object.modified > cutoff {
+                continue;
+            }
+        }
+
+        if delete_entry(namespace, cache, &object.key)
+            .await
+            .map_err(storage_error)?
+        {
+            purged.deleted += 1;
+            purged.bytes += object.size;
+        }
+    }
+
+    Ok(Json(purged))
+}
+
+/// Deletes every binary package matching `older_than` and/or `prefix`, except
+/// pinned ones.
+pub async fn cache_purge(
+    CurrentNamespace(namespace): CurrentNamespace,
+    Query(query): Query<PurgeQuery>,
+) -> Result<Json<Purged>, (StatusCode, String)> {
+    purge(&namespace, Cache::Binary, query).await
+}
+
+/// Deletes every asset matching `older_than` and/or `prefix`.
+pub async fn asset_purge(
+    CurrentNamespace(namespace): CurrentNamespace,
+    Query(query): Query<PurgeQuery>,
+) -> Result<Json<Purged>, (StatusCode, String)> {
+    purge(&namespace, Cache::Asset, query).await
+}
+
+/// Lists pinned binary packages.
+pub async fn pins_list(
+    CurrentNamespace(namespace): CurrentNamespace,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    refresh_pins(&namespace, Cache::Binary).await?;
+    Ok(Json(namespace.pins.list()))
+}
+
+/// Pins a binary package, which need not have been uploaded yet.
+pub async fn pin_put(
+    CurrentNamespace(namespace): CurrentNamespace,
+    Path(HashPath { hash }): Path<HashPath>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    validate_key(&hash)?;
+
+    let added = namespace.pins.pin(&hash).await.map_err(storage_error)?;
+    if !added {
+        return Ok(StatusCode::OK);
+    }
+    info!("Pinned {} in {}", hash, namespace.name);
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn pin_delete(
+    CurrentNamespace(namespace): CurrentNamespace,
+    Path(HashPath { hash }): Path<HashPath>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    validate_key(&hash)?;
+
+    let removed = namespace.pins.unpin(&hash).await.map_err(storage_error)?;
+    if !removed {
+        return Err((StatusCode::NOT_FOUND, format!("{} is not pinned", hash)));
+    }
+    info!("Unpinned {} in {}", hash, namespace.name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+pub struct UsageReport {
+    namespace: String,
+    /// Absent without a quota
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<u64>,
+    /// What counts against the quota: everything stored plus `reserved`
+    used: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available: Option<u64>,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+/// Reports how much of its quota the namespace uses.
+pub async fn usage_get(CurrentNamespace(namespace): CurrentNamespace) -> Json<UsageReport> {
+    let usage = namespace.quota.usage();
+    let quota = namespace.quota.limit();
+    Json(UsageReport {
+        namespace: namespace.name.clone(),
+        quota,
+        used: usage.total(),
+        available: quota.map(|quota| quota.saturating_sub(usage.total())),
+        usage,
+    })
+}
@@ -0,0 +1,142 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use axum::{
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::namespace::CurrentNamespace;
+
+/// What a token allows its bearer to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// `GET` and `HEAD`
+    Read,
+    /// Everything `Read` allows plus `PUT`
+    ReadWrite,
+    /// Everything, including the `DELETE`s that remove, purge or unpin entries
+    /// and pinning them
+    Admin,
+}
+
+impl Scope {
+    fn parse(s: &str) -> Option<Scope> {
+        match s {
+            "read" => Some(Scope::Read),
+            "readwrite" => Some(Scope::ReadWrite),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+
+    /// `path` is relative to the namespace.
+    fn required_for(method: &Method, path: &str) -> Scope {
+        if method == Method::GET || method == Method::HEAD {
+            Scope::Read
+        } else if method == Method::DELETE || path.starts_with("/api/pins/") {
+            // deleting is destructive and never needed by CI publishers, and
+            // pinned entries can only be deleted once unpinned again
+            Scope::Admin
+        } else {
+            Scope::ReadWrite
+        }
+    }
+}
+
+/// The configured bearer tokens. With no tokens at all, authentication is
+/// disabled and everyone can read and write.
+#[derive(Default)]
+pub struct Tokens {
+    tokens: HashMap<String, Scope>,
+}
+
+impl Tokens {
+    /// Adds tokens from a list of `<scope>:<token>` entries separated by commas
+    /// or newlines, where scope is `read`, `readwrite` or `admin`. Blank
+    /// entries and lines starting with `#` are ignored.
+    pub fn add_from_str(&mut self, s: &str) -> io::Result<()> {
+        for entry in s.split(['\n', ',']) {
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            let parsed = entry
+                .split_once(':')
+                .and_then(|(scope, token)| Some((Scope::parse(scope)?, token)))
+                .filter(|(_, token)| !token.is_empty());
+            let Some((scope, token)) = parsed else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid token entry '{}', expected read:<token>, readwrite:<token> or admin:<token>",
+                        entry
+                    ),
+                ));
+            };
+            self.tokens.insert(token.to_owned(), scope);
+        }
+        Ok(())
+    }
+
+    pub fn add_from_file(&mut self, path: &Path) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        self.add_from_str(&contents)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    fn scope(&self, token: &str) -> Option<Scope> {
+        self.tokens.get(token).copied()
+    }
+}
+
+fn unauthorized(message: &'static str) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        message,
+    )
+        .into_response()
+}
+
+/// Middleware rejecting requests whose `Authorization: Bearer` token does not
+/// grant the scope the request method needs in the namespace requested.
+pub async fn require_token<B>(
+    CurrentNamespace(namespace): CurrentNamespace,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let tokens = &namespace.tokens;
+    if tokens.is_empty() {
+        return next.run(request).await;
+    }
+
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let Some(token) = token else {
+        return unauthorized("missing bearer token");
+    };
+    let Some(scope) = tokens.scope(token.trim()) else {
+        return unauthorized("invalid bearer token");
+    };
+
+    // nested routes see their path without the namespace prefix
+    let required = Scope::required_for(request.method(), request.uri().path());
+    if scope < required {
+        let message = match required {
+            Scope::Admin => "deleting and pinning require an admin token",
+            _ => "token is read-only",
+        };
+        return (StatusCode::FORBIDDEN, message).into_response();
+    }
+
+    next.run(request).await
+}
@@ -0,0 +1,146 @@
+//! Validators, conditional requests and `Range` handling for entry
+//! downloads.
+
+use std::{
+    ops::Range,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{header, HeaderMap, HeaderValue};
+
+use crate::storage::ObjectMeta;
+
+/// What part of an entry a `GET` should send.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Selection {
+    Full,
+    Partial(Range<u64>),
+    /// The range starts beyond the end of the entry, answered with 416
+    Unsatisfiable,
+}
+
+/// The entity tag of an entry. Entries are content-addressed, so the key
+/// identifies the content.
+pub fn etag(key: &str) -> String {
+    format!("\"{}\"", key)
+}
+
+/// Truncates to whole seconds, the resolution of HTTP dates.
+fn http_time(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// `ETag`, `Last-Modified` and `Accept-Ranges` for `meta`, sent with every
+/// `GET` and `HEAD` of it.
+pub fn entry_headers(meta: &ObjectMeta) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&etag(&meta.key)).unwrap(),
+    );
+    headers.insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&httpdate::fmt_http_date(meta.modified)).unwrap(),
+    );
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers
+}
+
+/// Whether the client's copy is current according to `If-None-Match` or,
+/// without that, `If-Modified-Since`, so 304 can be sent instead.
+pub fn is_not_modified(headers: &HeaderMap, meta: &ObjectMeta) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        let etag = etag(&meta.key);
+        // weak comparison, so `W/` prefixes are ignored
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+    }
+
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .is_some_and(|since| http_time(meta.modified) <= since)
+}
+
+/// Whether an `If-Range` validator still matches `meta`, so the range may be
+/// served.
+fn if_range_matches(value: &HeaderValue, meta: &ObjectMeta) -> bool {
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    let value = value.trim();
+
+    // weak entity tags never match for If-Range
+    if value.starts_with('"') {
+        return value == etag(&meta.key);
+    }
+    httpdate::parse_http_date(value).is_ok_and(|date| date == http_time(meta.modified))
+}
+
+/// Parses a `Range` header for an entry of `size` bytes.
+///
+/// Only a single `bytes` range is supported. Requests for several ranges, and
+/// anything malformed, are ignored and get the whole entry, as RFC 9110
+/// allows.
+fn parse_range(value: &str, size: u64) -> Selection {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return Selection::Full;
+    };
+    if spec.contains(',') {
+        return Selection::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Selection::Full;
+    };
+
+    match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => Selection::Unsatisfiable,
+            Ok(_) if size == 0 => Selection::Unsatisfiable,
+            Ok(length) => Selection::Partial(size.saturating_sub(length)..size),
+            Err(_) => Selection::Full,
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return Selection::Full;
+            };
+            let end = if end.is_empty() {
+                size
+            } else {
+                match end.parse::<u64>() {
+                    Ok(end) if end >= start => end.saturating_add(1).min(size),
+                    _ => return Selection::Full,
+                }
+            };
+
+            if start >= size {
+                Selection::Unsatisfiable
+            } else {
+                Selection::Partial(start..end)
+            }
+        }
+    }
+}
+
+/// Decides what part of `meta` to send for a `GET` with `headers`.
+pub fn select_range(headers: &HeaderMap, meta: &ObjectMeta) -> Selection {
+    let Some(range) = headers.get(header::RANGE).and_then(|v| v.to_str().ok()) else {
+        return Selection::Full;
+    };
+    if let Some(if_range) = headers.get(header::IF_RANGE) {
+        if !if_range_matches(if_range, meta) {
+            return Selection::Full;
+        }
+    }
+    parse_range(range, meta.size)
+}
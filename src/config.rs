@@ -0,0 +1,409 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use std::time::Duration;
+
+use crate::{
+    auth::Tokens,
+    eviction::{parse_size, Limits},
+    namespace::DEFAULT_NAMESPACE,
+    retention::{parse_duration, Retention},
+    Args,
+};
+
+/// Directory under each root that other namespaces get their own roots in,
+/// unless they configure some. Hidden, so the default namespace skips it.
+const NAMESPACES_DIR: &str = ".namespaces";
+
+/// First path segments that routes already use, so namespaces cannot.
+const RESERVED_NAMESPACES: &[&str] = &[
+    DEFAULT_NAMESPACE,
+    "api",
+    "asset",
+    "cache",
+    "metrics",
+    "status",
+    "ui",
+];
+
+/// What a `PUT` does when the entry already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OverwritePolicy {
+    /// Answer 409 Conflict
+    Reject,
+    /// Answer 200 without reading the upload
+    #[default]
+    Ignore,
+    /// Store the upload in place of the existing entry
+    Replace,
+}
+
+/// The effective server settings, from the config file and command line.
+#[derive(Clone, Debug, Serialize)]
+pub struct Config {
+    pub binary_root: PathBuf,
+    pub asset_root: PathBuf,
+    pub port: u16,
+    pub local_addr: IpAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_binary_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_asset_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_binary_idle: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_binary_age: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_binary_keep: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_asset_idle: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_asset_age: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_asset_keep: Option<usize>,
+    pub overwrite: OverwritePolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s3_bucket: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s3_endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_token: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_token: Option<String>,
+    /// Caches served under `/<name>/` besides the default one
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub namespaces: BTreeMap<String, NamespaceConfig>,
+}
+
+/// A `[namespaces.<name>]` table. Limits not set here are taken from the top
+/// level, while tokens are never shared: a namespace needs its own as soon as
+/// the default namespace has any. `upstream` and `peers` only apply to the
+/// default namespace.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceConfig {
+    /// [default: `<binary_root>/.namespaces/<name>`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binary_root: Option<PathBuf>,
+    /// [default: `<asset_root>/.namespaces/<name>`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset_root: Option<PathBuf>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_binary_size: Option<u64>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_asset_size: Option<u64>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<String>,
+}
+
+/// The config file as written, where everything is optional.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    binary_root: Option<PathBuf>,
+    asset_root: Option<PathBuf>,
+    port: Option<u16>,
+    local_addr: Option<IpAddr>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    max_binary_size: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    max_asset_size: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    quota: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    max_binary_idle: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    max_binary_age: Option<u64>,
+    min_binary_keep: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    max_asset_idle: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    max_asset_age: Option<u64>,
+    min_asset_keep: Option<usize>,
+    overwrite: Option<OverwritePolicy>,
+    s3_bucket: Option<String>,
+    s3_endpoint: Option<String>,
+    token_file: Option<PathBuf>,
+    tokens: Option<Vec<String>>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    upstream: Option<String>,
+    upstream_token: Option<String>,
+    peers: Option<Vec<String>>,
+    peer_token: Option<String>,
+    namespaces: Option<BTreeMap<String, NamespaceConfig>>,
+}
+
+/// Accepts sizes either as a number of bytes or a string like `"50G"`.
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Size::Text(text)) => parse_size(&text).map(Some).map_err(de::Error::custom),
+    }
+}
+
+/// Accepts durations either as a number of seconds or a string like `"30d"`.
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Secs {
+        Secs(u64),
+        Text(String),
+    }
+
+    match Option::<Secs>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Secs::Secs(secs)) => Ok(Some(secs)),
+        Some(Secs::Text(text)) => parse_duration(&text).map(Some).map_err(de::Error::custom),
+    }
+}
+
+impl Config {
+    /// Loads `--config` if given and applies the command line on top of it.
+    pub fn load(args: &Args) -> anyhow::Result<Config> {
+        let file = match &args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("reading config file {}", path.display()))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("parsing config file {}", path.display()))?
+            }
+            None => ConfigFile::default(),
+        };
+
+        let Some(binary_root) = args.binary_root.clone().or(file.binary_root) else {
+            bail!("binary_root must be set, either with --binary-root or in the config file");
+        };
+        let Some(asset_root) = args.asset_root.clone().or(file.asset_root) else {
+            bail!("asset_root must be set, either with --asset-root or in the config file");
+        };
+
+        let config = Config {
+            binary_root,
+            asset_root,
+            port: args.port.or(file.port).unwrap_or(3000),
+            local_addr: args
+                .local_addr
+                .or(file.local_addr)
+                .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            max_binary_size: args.max_binary_size.or(file.max_binary_size),
+            max_asset_size: args.max_asset_size.or(file.max_asset_size),
+            quota: args.quota.or(file.quota),
+            max_binary_idle: args.max_binary_idle.or(file.max_binary_idle),
+            max_binary_age: args.max_binary_age.or(file.max_binary_age),
+            min_binary_keep: args.min_binary_keep.or(file.min_binary_keep),
+            max_asset_idle: args.max_asset_idle.or(file.max_asset_idle),
+            max_asset_age: args.max_asset_age.or(file.max_asset_age),
+            min_asset_keep: args.min_asset_keep.or(file.min_asset_keep),
+            overwrite: args.overwrite.or(file.overwrite).unwrap_or_default(),
+            s3_bucket: args.s3_bucket.clone().or(file.s3_bucket),
+            s3_endpoint: args.s3_endpoint.clone().or(file.s3_endpoint),
+            token_file: args.token_file.clone().or(file.token_file),
+            tokens: args.tokens.clone().or(file.tokens).unwrap_or_default(),
+            tls_cert: args.tls_cert.clone().or(file.tls_cert),
+            tls_key: args.tls_key.clone().or(file.tls_key),
+            upstream: args.upstream.clone().or(file.upstream),
+            upstream_token: args.upstream_token.clone().or(file.upstream_token),
+            peers: args.peers.clone().or(file.peers).unwrap_or_default(),
+            peer_token: args.peer_token.clone().or(file.peer_token),
+            namespaces: file.namespaces.unwrap_or_default(),
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.s3_endpoint.is_some() && self.s3_bucket.is_none() {
+            bail!("s3_endpoint is set but s3_bucket is not");
+        }
+        for url in self.upstream.iter().chain(&self.peers) {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                bail!(
+                    "upstream and peers must be http:// or https:// URLs, got {}",
+                    url
+                );
+            }
+        }
+        if self.upstream_token.is_some() && self.upstream.is_none() {
+            bail!("upstream_token is set but upstream is not");
+        }
+        if self.peer_token.is_some() && self.peers.is_empty() {
+            bail!("peer_token is set but peers is not");
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            bail!("tls_cert and tls_key must be set together");
+        }
+        self.tokens()?;
+
+        for name in self.namespaces.keys() {
+            let valid = name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+                && name
+                    .bytes()
+                    .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_'));
+            if !valid {
+                bail!(
+                    "namespace names must be lowercase letters, digits, '-' and '_', got '{}'",
+                    name
+                );
+            }
+            if RESERVED_NAMESPACES.contains(&name.as_str()) {
+                bail!("'{}' cannot be used as a namespace name", name);
+            }
+            let tokens = self
+                .for_namespace(name)?
+                .tokens()
+                .with_context(|| format!("tokens of namespace {}", name))?;
+            if tokens.is_empty() && !self.tokens()?.is_empty() {
+                bail!(
+                    "namespace {} has no tokens, which would let anyone use it \
+                     while the default namespace requires tokens",
+                    name
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every namespace, starting with the default one.
+    pub fn namespace_names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(DEFAULT_NAMESPACE).chain(self.namespaces.keys().map(String::as_str))
+    }
+
+    /// The settings of namespace `name`, as if it were the only cache served.
+    pub fn for_namespace(&self, name: &str) -> anyhow::Result<Config> {
+        if name == DEFAULT_NAMESPACE {
+            return Ok(Config {
+                namespaces: BTreeMap::new(),
+                ..self.clone()
+            });
+        }
+        let Some(namespace) = self.namespaces.get(name) else {
+            bail!("namespace {} is not configured", name);
+        };
+
+        let root = |own: &Option<PathBuf>, default: &PathBuf| {
+            own.clone()
+                .unwrap_or_else(|| default.join(NAMESPACES_DIR).join(name))
+        };
+        Ok(Config {
+            binary_root: root(&namespace.binary_root, &self.binary_root),
+            asset_root: root(&namespace.asset_root, &self.asset_root),
+            max_binary_size: namespace.max_binary_size.or(self.max_binary_size),
+            max_asset_size: namespace.max_asset_size.or(self.max_asset_size),
+            quota: namespace.quota.or(self.quota),
+            token_file: namespace.token_file.clone(),
+            tokens: namespace.tokens.clone(),
+            upstream: None,
+            upstream_token: None,
+            peers: Vec::new(),
+            peer_token: None,
+            namespaces: BTreeMap::new(),
+            ..self.clone()
+        })
+    }
+
+    pub fn binary_limits(&self) -> Limits {
+        Limits {
+            max_size: self.max_binary_size,
+            retention: Retention {
+                max_idle: self.max_binary_idle.map(Duration::from_secs),
+                max_age: self.max_binary_age.map(Duration::from_secs),
+                min_keep: self.min_binary_keep.unwrap_or(0),
+            },
+        }
+    }
+
+    pub fn asset_limits(&self) -> Limits {
+        Limits {
+            max_size: self.max_asset_size,
+            retention: Retention {
+                max_idle: self.max_asset_idle.map(Duration::from_secs),
+                max_age: self.max_asset_age.map(Duration::from_secs),
+                min_keep: self.min_asset_keep.unwrap_or(0),
+            },
+        }
+    }
+
+    /// Reads the tokens from `tokens` and `token_file`.
+    pub fn tokens(&self) -> anyhow::Result<Tokens> {
+        let mut tokens = Tokens::default();
+        if let Some(token_file) = &self.token_file {
+            tokens.add_from_file(token_file)?;
+        }
+        for entry in &self.tokens {
+            tokens.add_from_str(entry)?;
+        }
+        Ok(tokens)
+    }
+
+    /// Renders the configuration as TOML, with token values redacted.
+    pub fn to_toml(&self) -> String {
+        let mut redacted = self.clone();
+        for token in [&mut redacted.upstream_token, &mut redacted.peer_token] {
+            if token.is_some() {
+                *token = Some("<redacted>".to_owned());
+            }
+        }
+        let namespace_tokens = redacted
+            .namespaces
+            .values_mut()
+            .flat_map(|namespace| &mut namespace.tokens);
+        for entry in redacted.tokens.iter_mut().chain(namespace_tokens) {
+            if let Some((scope, _)) = entry.split_once(':') {
+                *entry = format!("{}:<redacted>", scope);
+            }
+        }
+
+        toml::to_string(&redacted).expect("config is always representable as TOML")
+    }
+}
@@ -0,0 +1,224 @@
+use std::{cmp::Reverse, io, sync::Arc, time::Duration};
+
+use tracing::{info, warn};
+
+use crate::{
+    access::AccessLog,
+    metrics::Metrics,
+    namespace::{Cache, Namespace},
+    pins::Pins,
+    retention::{self, Retention},
+    storage::{ObjectMeta, Storage},
+};
+
+/// How often each root is checked against its size limit.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Parses a byte size such as `500M` or `20G` (binary multiples).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return Err(format!("unknown size suffix '{}'", c)),
+            };
+            (&s[..i], multiplier)
+        }
+        _ => (s, 1),
+    };
+
+    let value: u64 = digits
+        .parse()
+        .map_err(|e| format!("invalid size '{}': {}", s, e))?;
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/// Everything that bounds what one root keeps.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Evict entries beyond this many bytes
+    pub max_size: Option<u64>,
+    pub retention: Retention,
+}
+
+impl Limits {
+    pub fn is_set(&self) -> bool {
+        self.max_size.is_some() || self.retention.is_set()
+    }
+}
+
+/// Deletes least-recently-read entries until at most `max_size` bytes are
+/// used, returning what remains.
+///
+/// Entries that were never read since the access log started are ranked by
+/// their upload time instead. Pinned entries are never evicted, but still
+/// count towards `max_size`.
+pub async fn evict(
+    storage: &dyn Storage,
+    mut entries: Vec<ObjectMeta>,
+    max_size: u64,
+    access_log: &AccessLog,
+    pins: Option<&Pins>,
+) -> io::Result<Vec<ObjectMeta>> {
+    let mut total: u64 = entries.iter().map(|e| e.size).sum();
+    if total <= max_size {
+        return Ok(entries);
+    }
+
+    // most recently read first, so evicting pops from the back
+    entries.sort_by_cached_key(|e| {
+        Reverse(
+            access_log
+                .get(&e.key)
+                .map_or(e.modified, |a| a.last_access.max(e.modified)),
+        )
+    });
+
+    let mut pinned = Vec::new();
+    while total > max_size {
+        let Some(entry) = entries.pop() else {
+            break;
+        };
+        if pins.is_some_and(|pins| pins.contains(&entry.key)) {
+            pinned.push(entry);
+            continue;
+        }
+
+        storage.delete(&entry.key).await?;
+        access_log.remove(&entry.key);
+        total -= entry.size;
+
+        info!(
+            "Evicted {} ({})",
+            entry.key,
+            human_bytes::human_bytes(entry.size as f64)
+        );
+    }
+    entries.extend(pinned);
+
+    Ok(entries)
+}
+
+/// Periodically updates the usage metrics and quota usage of `cache` in
+/// `namespace`, persists its access log, expires entries according to
+/// `limits.retention` and, if `limits.max_size` is set, evicts entries that
+/// exceed it.
+pub async fn eviction_task(
+    namespace: Arc<Namespace>,
+    cache: Cache,
+    limits: Limits,
+    metrics: Arc<Metrics>,
+) {
+    let storage = namespace.storage(cache).clone();
+    let access_log = namespace.access_log(cache).clone();
+    let pins = namespace.pins(cache).cloned();
+
+    let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        // other servers sharing the storage may have pinned entries since
+        if let Some(pins) = &pins {
+            if let Err(e) = pins.refresh().await {
+                warn!("Failed to refresh pins of {}: {}", namespace.name, e);
+                continue;
+            }
+        }
+
+        let mut entries = match storage.list().await {
+            Ok(entries) => Some(entries),
+            Err(e) => {
+                warn!(
+                    "Failed to list {} entries in {}: {}",
+                    cache.name(),
+                    namespace.name,
+                    e
+                );
+                None
+            }
+        };
+
+        if let Some(listed) = entries.take() {
+            match retention::expire(
+                storage.as_ref(),
+                listed,
+                &limits.retention,
+                &access_log,
+                pins.as_deref(),
+            )
+            .await
+            {
+                Ok(expiry) => {
+                    for (_, reason) in &expiry.expired {
+                        metrics
+                            .deleted
+                            .with_label_values(&[&namespace.name, cache.name(), reason.label()])
+                            .inc();
+                    }
+                    entries = Some(expiry.remaining);
+                }
+                Err(e) => warn!(
+                    "Failed to expire {} entries in {}: {}",
+                    cache.name(),
+                    namespace.name,
+                    e
+                ),
+            }
+        }
+
+        if let Some(max_size) = limits.max_size {
+            if let Some(listed) = entries.take() {
+                let before = listed.len();
+                match evict(
+                    storage.as_ref(),
+                    listed,
+                    max_size,
+                    &access_log,
+                    pins.as_deref(),
+                )
+                .await
+                {
+                    Ok(remaining) => {
+                        metrics
+                            .deleted
+                            .with_label_values(&[&namespace.name, cache.name(), "size"])
+                            .inc_by((before - remaining.len()) as u64);
+                        entries = Some(remaining);
+                    }
+                    Err(e) => warn!(
+                        "Failed to evict {} entries in {}: {}",
+                        cache.name(),
+                        namespace.name,
+                        e
+                    ),
+                }
+            }
+        }
+
+        if let Some(entries) = entries {
+            let total: u64 = entries.iter().map(|e| e.size).sum();
+            namespace.quota.set_stored(cache, total);
+            metrics
+                .storage_bytes
+                .with_label_values(&[&namespace.name, cache.name()])
+                .set(total as i64);
+            metrics
+                .storage_entries
+                .with_label_values(&[&namespace.name, cache.name()])
+                .set(entries.len() as i64);
+        }
+
+        let access_log = access_log.clone();
+        match tokio::task::spawn_blocking(move || access_log.save()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to save access log: {}", e),
+            Err(e) => warn!("Saving access log panicked: {}", e),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    package::{self, PackageInfo},
+    storage::{open_local, ObjectMeta, Storage},
+};
+
+/// File under the binary root that the index is kept in.
+const INDEX_FILE: &str = ".index.sqlite";
+
+/// How often the index is brought in line with what is actually stored.
+const INDEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A binary package as returned by `/api/packages`.
+#[derive(Debug, Serialize)]
+pub struct PackageRecord {
+    pub abi: String,
+    pub port: String,
+    pub version: Option<String>,
+    pub triplet: Option<String>,
+    pub features: Vec<String>,
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub uploaded: u64,
+}
+
+/// SQLite index of which port, version and triplet each binary package is a
+/// build of, so packages can be found without knowing their hash.
+///
+/// Packages that could not be parsed are still recorded, without a port, so
+/// they are not read again on every sync.
+pub struct Index {
+    conn: Mutex<Connection>,
+}
+
+impl Index {
+    /// Opens or creates the index in `binary_root`.
+    pub fn open(binary_root: &Path) -> io::Result<Index> {
+        std::fs::create_dir_all(binary_root)?;
+        let conn = Connection::open(binary_root.join(INDEX_FILE)).map_err(io::Error::other)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS packages (
+                hash TEXT PRIMARY KEY,
+                port TEXT,
+                version TEXT,
+                triplet TEXT,
+                features TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                uploaded INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS packages_port_triplet ON packages (port, triplet);",
+        )
+        .map_err(io::Error::other)?;
+
+        Ok(Index {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Records `hash`, replacing what was recorded for it before.
+    pub fn insert(
+        &self,
+        hash: &str,
+        info: Option<&PackageInfo>,
+        size: u64,
+        uploaded: SystemTime,
+    ) -> io::Result<()> {
+        let uploaded = uploaded
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO packages
+                    (hash, port, version, triplet, features, size, uploaded)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    hash,
+                    info.map(|i| &i.port),
+                    info.and_then(|i| i.version.as_ref()),
+                    info.and_then(|i| i.triplet.as_ref()),
+                    info.map(|i| i.features.join(";")).unwrap_or_default(),
+                    size,
+                    uploaded,
+                ],
+            )
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, hash: &str) -> io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM packages WHERE hash = ?1", [hash])
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// Returns the packages for `port` and `triplet`, or all of them for
+    /// whichever is `None`.
+    pub fn query(
+        &self,
+        port: Option<&str>,
+        triplet: Option<&str>,
+    ) -> io::Result<Vec<PackageRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare_cached(
+                "SELECT hash, port, version, triplet, features, size, uploaded
+                FROM packages
+                WHERE port IS NOT NULL
+                    AND (?1 IS NULL OR port = ?1)
+                    AND (?2 IS NULL OR triplet = ?2)
+                ORDER BY port, triplet, version, uploaded",
+            )
+            .map_err(io::Error::other)?;
+
+        let records = statement
+            .query_map(params![port, triplet], record_from_row)
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(io::Error::other)?;
+        Ok(records)
+    }
+
+    fn hashes(&self) -> io::Result<HashSet<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare_cached("SELECT hash FROM packages")
+            .map_err(io::Error::other)?;
+        let hashes = statement
+            .query_map([], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(io::Error::other)?;
+        Ok(hashes)
+    }
+
+    /// Drops packages that are no longer stored and indexes those that were
+    /// stored without going through `cache_put` (upstream pulls, imports, or
+    /// uploads from before the index existed).
+    async fn sync(&self, storage: &dyn Storage, entries: &[ObjectMeta]) -> io::Result<()> {
+        let mut stale = tokio::task::block_in_place(|| self.hashes())?;
+
+        for entry in entries {
+            if stale.remove(&entry.key) {
+                continue;
+            }
+
+            let Some(file) = open_local(storage, &entry.key).await? else {
+                continue;
+            };
+            let info =
+                tokio::task::block_in_place(|| package::read_info(&mut package::open(file)?));
+            let info = match info {
+                Ok(info) => Some(info),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    warn!("Not indexing {}: {}", entry.key, e);
+                    None
+                }
+                Err(e) => return Err(e),
+            };
+
+            tokio::task::block_in_place(|| {
+                self.insert(&entry.key, info.as_ref(), entry.size, entry.modified)
+            })?;
+            info!("Indexed {}", entry.key);
+        }
+
+        for hash in stale {
+            tokio::task::block_in_place(|| self.remove(&hash))?;
+        }
+        Ok(())
+    }
+}
+
+fn record_from_row(row: &rusqlite::Row) -> rusqlite::Result<PackageRecord> {
+    let features: String = row.get(4)?;
+    Ok(PackageRecord {
+        abi: row.get(0)?,
+        port: row.get(1)?,
+        version: row.get(2)?,
+        triplet: row.get(3)?,
+        features: features
+            .split(';')
+            .filter(|f| !f.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        size: row.get(5)?,
+        uploaded: row.get(6)?,
+    })
+}
+
+/// Periodically syncs `index` with the packages in `storage`.
+pub async fn index_task(storage: Arc<dyn Storage>, index: Arc<Index>) {
+    let mut interval = tokio::time::interval(INDEX_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let entries = match storage.list().await {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to list binary entries for the index: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = index.sync(storage.as_ref(), &entries).await {
+            warn!("Failed to update the package index: {}", e);
+        }
+    }
+}
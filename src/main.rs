@@ -1,170 +1,1161 @@
 use axum::{
-    body::{Bytes, StreamBody},
-    extract::{BodyStream, Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, head, put},
-    Router,
+    body::StreamBody,
+    extract::{BodyStream, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, head, put},
+    Json, Router,
 };
 use clap::Parser;
-use futures::{Stream, TryStreamExt};
+use futures::{StreamExt, TryStreamExt};
 use std::{
-    fs, io,
+    collections::HashMap,
+    io,
     net::{IpAddr, SocketAddr},
     path::PathBuf,
     sync::Arc,
+    time::SystemTime,
 };
-use tokio::{fs::File, io::BufWriter};
-use tokio_util::io::{ReaderStream, StreamReader};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{info, warn};
+
+mod access;
+mod admin;
+mod api;
+mod auth;
+mod conditional;
+mod config;
+mod eviction;
+mod index;
+mod metrics;
+mod namespace;
+mod package;
+mod pins;
+mod quota;
+mod replication;
+mod retention;
+mod storage;
+#[cfg(unix)]
+mod tls;
+mod ui;
+mod uploads;
+mod upstream;
+
+use admin::Caches;
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use conditional::Selection;
+use config::{Config, OverwritePolicy};
+use index::PackageRecord;
+use metrics::Metrics;
+use namespace::{Cache, CurrentNamespace, Namespace, DEFAULT_NAMESPACE};
+use pins::Pins;
+use prometheus::IntCounter;
+use quota::Reservation;
+use replication::PeerLag;
+use storage::{ByteStream, FsStorage, Layout, ObjectMeta, S3Storage, Storage, Validate};
+use uploads::UploadGuard;
 
 #[derive(clap::Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand, the server is run as with `serve`
+    #[clap(flatten)]
+    serve: Args,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Run the cache server
+    Serve(Box<Args>),
+    /// Print entry counts and sizes per prefix directory
+    Stats(RootArgs),
+    /// Check that every binary package is a readable zip and every asset
+    /// matches its digest
+    Verify(RootArgs),
+    /// Remove abandoned staging files and evict entries beyond the
+    /// configured size limits
+    Gc {
+        #[clap(flatten)]
+        roots: RootArgs,
+
+        #[clap(long, value_parser = eviction::parse_size)]
+        max_binary_size: Option<u64>,
+
+        #[clap(long, value_parser = eviction::parse_size)]
+        max_asset_size: Option<u64>,
+    },
+    /// Copy entries from a directory or tarball created by `export`
+    Import {
+        #[clap(flatten)]
+        roots: RootArgs,
+
+        /// Directory, `.tar`, `.tar.gz` or `.tgz` to read from
+        from: PathBuf,
+
+        /// Replace entries that already exist
+        #[clap(long)]
+        overwrite: bool,
+    },
+    /// Copy all entries to a directory or tarball
+    Export {
+        #[clap(flatten)]
+        roots: RootArgs,
+
+        /// Directory to create, or a `.tar`, `.tar.gz` or `.tgz` file
+        to: PathBuf,
+    },
+}
+
+/// Which cache the maintenance subcommands work on.
+#[derive(clap::Args)]
+struct RootArgs {
+    /// Config file to take the roots (and S3 settings) from
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    #[clap(long)]
+    binary_root: Option<PathBuf>,
+
+    #[clap(long)]
+    asset_root: Option<PathBuf>,
+
+    /// Namespace from the config file to work on
+    #[clap(long, default_value = DEFAULT_NAMESPACE)]
+    namespace: String,
+}
+
+impl RootArgs {
+    /// Loads the settings of the selected namespace.
+    fn load(self) -> anyhow::Result<Config> {
+        let config = Config::load(&Args {
+            config: self.config,
+            binary_root: self.binary_root,
+            asset_root: self.asset_root,
+            ..Args::default()
+        })?;
+        config.for_namespace(&self.namespace)
+    }
+
+    fn open_caches(self) -> anyhow::Result<Caches> {
+        let namespace = self.namespace.clone();
+        open_caches(&self.load()?, &namespace)
+    }
+}
+
+/// Settings given here override those from the `--config` file.
+#[derive(clap::Args, Default)]
 struct Args {
+    /// TOML file with the settings below, using their names with underscores
+    /// (e.g. `binary_root = "/srv/vcpkg/binary"`)
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration as TOML and exit
+    #[clap(long)]
+    print_config: bool,
+
+    #[clap(long)]
+    binary_root: Option<PathBuf>,
+
+    #[clap(long)]
+    asset_root: Option<PathBuf>,
+
+    /// [default: 3000]
+    #[clap(long)]
+    port: Option<u16>,
+
+    /// [default: 127.0.0.1]
+    #[clap(long)]
+    local_addr: Option<IpAddr>,
+
+    /// Evict least recently read binary packages beyond this size (e.g. `50G`)
+    #[clap(long, value_parser = eviction::parse_size)]
+    max_binary_size: Option<u64>,
+
+    /// Evict least recently read assets beyond this size (e.g. `50G`)
+    #[clap(long, value_parser = eviction::parse_size)]
+    max_asset_size: Option<u64>,
+
+    /// Answer uploads with 507 Insufficient Storage once binary packages and
+    /// assets together take up this much (e.g. `200G`)
+    #[clap(long, value_parser = eviction::parse_size)]
+    quota: Option<u64>,
+
+    /// Expire binary packages not read for this long (e.g. `30d`)
+    #[clap(long, value_parser = retention::parse_duration)]
+    max_binary_idle: Option<u64>,
+
+    /// Expire binary packages uploaded this long ago, even if still read
+    #[clap(long, value_parser = retention::parse_duration)]
+    max_binary_age: Option<u64>,
+
+    /// Never expire binary packages once only this many are left
+    #[clap(long)]
+    min_binary_keep: Option<usize>,
+
+    /// Expire assets not read for this long (e.g. `90d`)
+    #[clap(long, value_parser = retention::parse_duration)]
+    max_asset_idle: Option<u64>,
+
+    /// Expire assets downloaded this long ago, even if still read
+    #[clap(long, value_parser = retention::parse_duration)]
+    max_asset_age: Option<u64>,
+
+    /// Never expire assets once only this many are left
+    #[clap(long)]
+    min_asset_keep: Option<usize>,
+
+    /// What uploads of entries that already exist do [default: ignore]
+    #[clap(long, value_enum)]
+    overwrite: Option<OverwritePolicy>,
+
+    /// Store entries and pins in this S3 bucket (under `binary/`, `asset/`
+    /// and `pins/`, or `namespaces/<name>/` for other namespaces) instead of
+    /// the roots, which then only hold local bookkeeping. Credentials are
+    /// read from the `AWS_*` environment variables.
+    #[clap(long)]
+    s3_bucket: Option<String>,
+
+    /// Endpoint of an S3-compatible service such as MinIO
+    #[clap(long)]
+    s3_endpoint: Option<String>,
+
+    /// File with one `read:<token>`, `readwrite:<token>` or `admin:<token>`
+    /// entry per line. Only `admin` tokens can delete, purge and pin entries.
+    /// Once any token is configured, `/cache` and `/asset` require
+    /// `Authorization: Bearer <token>`
+    #[clap(long, env = "VCPKG_CACHE_TOKEN_FILE")]
+    token_file: Option<PathBuf>,
+
+    /// Comma separated `read:<token>`/`readwrite:<token>`/`admin:<token>`
+    /// entries, in addition to those from `--token-file`
+    #[clap(
+        long,
+        env = "VCPKG_CACHE_TOKENS",
+        hide_env_values = true,
+        value_delimiter = ','
+    )]
+    tokens: Option<Vec<String>>,
+
+    /// PEM certificate chain to serve HTTPS with; reloaded on `SIGHUP`
+    #[clap(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `--tls-cert`
     #[clap(long)]
-    binary_root: PathBuf,
+    tls_key: Option<PathBuf>,
 
+    /// Base URL of another binary cache that misses are fetched from and
+    /// stored locally (e.g. `https://central-cache:3000`)
     #[clap(long)]
-    asset_root: PathBuf,
+    upstream: Option<String>,
+
+    /// Bearer token sent to `--upstream`
+    #[clap(long, env = "VCPKG_CACHE_UPSTREAM_TOKEN", hide_env_values = true)]
+    upstream_token: Option<String>,
 
-    #[clap(long, default_value = "3000")]
-    port: u16,
+    /// Comma separated base URLs of caches that new binary packages are
+    /// pushed to. Pending pushes are queued under the binary root, and each
+    /// peer's lag is reported on `/status/replication`
+    #[clap(long, value_delimiter = ',')]
+    peers: Option<Vec<String>>,
+
+    /// Bearer token sent to `--peers`, which needs `readwrite` scope
+    #[clap(long, env = "VCPKG_CACHE_PEER_TOKEN", hide_env_values = true)]
+    peer_token: Option<String>,
+}
 
-    #[clap(long, default_value = "127.0.0.1")]
-    local_addr: IpAddr,
+struct AppState {
+    /// Every namespace by name, including the default one
+    namespaces: HashMap<String, Arc<Namespace>>,
+    metrics: Arc<Metrics>,
+    overwrite: OverwritePolicy,
+}
+
+/// The key of `/cache/:hash` and similar routes, which may also have a
+/// `:namespace`.
+#[derive(serde::Deserialize)]
+struct HashPath {
+    hash: String,
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> anyhow::Result<()> {
     // initialize tracing
     tracing_subscriber::fmt::init();
 
-    let args = Arc::new(Args::parse());
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve(Box::new(cli.serve)));
 
-    // build our application with a route
-    let app = Router::new()
+    match command {
+        Command::Serve(args) => serve(*args).await,
+        Command::Stats(roots) => admin::stats(&roots.open_caches()?).await,
+        Command::Verify(roots) => {
+            let corrupt = admin::verify(&roots.open_caches()?).await?;
+            if corrupt > 0 {
+                anyhow::bail!("found {} corrupt entries", corrupt);
+            }
+            Ok(())
+        }
+        Command::Gc {
+            roots,
+            max_binary_size,
+            max_asset_size,
+        } => {
+            let namespace = roots.namespace.clone();
+            let mut config = roots.load()?;
+            config.max_binary_size = max_binary_size.or(config.max_binary_size);
+            config.max_asset_size = max_asset_size.or(config.max_asset_size);
+            let pins = load_pins(&config, &namespace).await?;
+            admin::gc(&config, &open_caches(&config, &namespace)?, &pins).await
+        }
+        Command::Import {
+            roots,
+            from,
+            overwrite,
+        } => admin::import(&roots.open_caches()?, &from, overwrite).await,
+        Command::Export { roots, to } => admin::export(&roots.open_caches()?, &to).await,
+    }
+}
+
+/// Loads the pins of `namespace`, which live in the bucket along with the
+/// entries they protect when using S3.
+async fn load_pins(config: &Config, namespace: &str) -> anyhow::Result<Pins> {
+    let pins = match &config.s3_bucket {
+        Some(bucket) => {
+            let storage = S3Storage::new(
+                bucket,
+                config.s3_endpoint.as_deref(),
+                &s3_prefix(namespace, "pins"),
+                Layout::Asset,
+            )?;
+            Pins::load_shared(Arc::new(storage)).await
+        }
+        None => Pins::load(&config.binary_root),
+    };
+    pins.with_context(|| format!("loading pins of {}", namespace))
+}
+
+/// Where `namespace` keeps `what` in an S3 bucket.
+fn s3_prefix(namespace: &str, what: &str) -> String {
+    if namespace == DEFAULT_NAMESPACE {
+        what.to_owned()
+    } else {
+        format!("namespaces/{}/{}", namespace, what)
+    }
+}
+
+/// Opens the storage of `namespace`, whose settings `config` holds.
+fn open_caches(config: &Config, namespace: &str) -> anyhow::Result<Caches> {
+    Ok(match &config.s3_bucket {
+        Some(bucket) => {
+            let endpoint = config.s3_endpoint.as_deref();
+            Caches {
+                binary: Arc::new(S3Storage::new(
+                    bucket,
+                    endpoint,
+                    &s3_prefix(namespace, "binary"),
+                    Layout::Binary,
+                )?),
+                asset: Arc::new(S3Storage::new(
+                    bucket,
+                    endpoint,
+                    &s3_prefix(namespace, "asset"),
+                    Layout::Asset,
+                )?),
+            }
+        }
+        None => Caches {
+            binary: Arc::new(FsStorage::new(config.binary_root.clone(), Layout::Binary)),
+            asset: Arc::new(FsStorage::new(config.asset_root.clone(), Layout::Asset)),
+        },
+    })
+}
+
+async fn serve(args: Args) -> anyhow::Result<()> {
+    let config = Config::load(&args)?;
+    if args.print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+
+    let addr = SocketAddr::from((config.local_addr, config.port));
+    let app = app(&config).await?;
+
+    tracing::debug!("listening on {}", addr);
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls = RustlsConfig::from_pem_file(cert, key)
+                .await
+                .with_context(|| format!("loading TLS certificate {}", cert.display()))?;
+            #[cfg(unix)]
+            tokio::spawn(tls::reload_on_sighup(
+                tls.clone(),
+                cert.clone(),
+                key.clone(),
+            ));
+
+            axum_server::bind_rustls(addr, tls)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        _ => {
+            // run our app with hyper
+            // `axum::Server` is a re-export of `hyper::Server`
+            axum::Server::bind(&addr)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts every namespace of `config` and routes requests to them.
+async fn app(config: &Config) -> anyhow::Result<Router> {
+    let metrics = Arc::new(Metrics::new());
+
+    let mut namespaces = HashMap::new();
+    for name in config.namespace_names() {
+        let namespace = Namespace::start(name, &config.for_namespace(name)?, &metrics).await?;
+        namespaces.insert(name.to_owned(), namespace);
+    }
+
+    let state = Arc::new(AppState {
+        namespaces,
+        metrics: metrics.clone(),
+        overwrite: config.overwrite,
+    });
+
+    // every namespace has the same routes, the default one without a prefix
+    let namespace_routes = Router::new()
         .route("/cache/:hash", get(cache_get))
-        .route("/status", get(|| async { "online" }))
         .route("/cache/:hash", head(cache_head))
         .route("/cache/:hash", put(cache_put))
+        .route("/cache/:hash", delete(api::cache_delete))
+        .route("/asset/:hash", get(asset_get))
+        .route("/asset/:hash", head(asset_head))
         .route("/asset/:hash", put(asset_put))
+        .route("/asset/:hash", delete(api::asset_delete))
+        .route("/api/cache", get(api::cache_list).delete(api::cache_purge))
+        .route("/api/asset", get(api::asset_list).delete(api::asset_purge))
+        .route("/api/packages", get(packages_get))
+        .route("/api/pins", get(api::pins_list))
+        .route("/api/usage", get(api::usage_get))
+        .route("/api/pins/:hash", put(api::pin_put).delete(api::pin_delete))
+        .route("/ui/entries", get(ui::entries_get))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_token,
+        ))
+        .route("/ui", get(ui::page_get));
+
+    // build our application with a route
+    let app = Router::new()
+        .merge(namespace_routes.clone())
+        .nest("/:namespace", namespace_routes)
+        .route("/status", get(|| async { "online" }))
+        .route("/status/replication", get(replication_status))
+        .route_layer(middleware::from_fn_with_state(
+            metrics.clone(),
+            metrics::track_requests,
+        ))
+        .route("/metrics", get(metrics::metrics_get).with_state(metrics))
         .layer(TraceLayer::new_for_http())
-        .with_state(args.clone());
+        .with_state(state);
 
-    // run our app with hyper
-    // `axum::Server` is a re-export of `hyper::Server`
-    let addr = SocketAddr::from((args.local_addr, args.port));
-    tracing::debug!("listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    Ok(app)
 }
 
-fn hash_to_file(root: &std::path::Path, hash: &str) -> Result<PathBuf, (StatusCode, String)> {
-    if hash.len() < 20 {
-        return Err((StatusCode::BAD_REQUEST, "hash too short".into()));
+/// Checks that `key` is a hex SHA-256 or SHA-512 digest, the only forms vcpkg
+/// uses for binary and asset cache keys.
+///
+/// Every handler goes through this before touching storage, so a valid key can
+/// never contain `..`, path separators or non-ASCII characters.
+fn validate_key(key: &str) -> Result<(), (StatusCode, String)> {
+    if key.len() != 64 && key.len() != 128 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "key must be a SHA-256 or SHA-512 hex digest, got {} characters",
+                key.len()
+            ),
+        ));
     }
 
-    let mut cache_path = root.to_owned();
-    cache_path.push(&hash[..2]);
-    cache_path.push(hash.to_owned() + ".zip");
+    if !storage::is_valid_key(key) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "key must only contain lowercase hex digits".into(),
+        ));
+    }
 
-    Ok(cache_path)
+    Ok(())
 }
 
-async fn cache_get(
-    State(state): State<Arc<Args>>,
-    Path(hash): Path<String>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let cache_path = hash_to_file(&state.binary_root, &hash)?;
+fn storage_error(e: io::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
 
-    if !cache_path.exists() {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("{} does not exist", cache_path.display()),
-        ));
+/// Maps a failed upload to a response, blaming the client for bad content.
+fn upload_error(e: io::Error) -> (StatusCode, String) {
+    match e.kind() {
+        io::ErrorKind::InvalidData => (StatusCode::BAD_REQUEST, e.to_string()),
+        io::ErrorKind::StorageFull => (StatusCode::INSUFFICIENT_STORAGE, e.to_string()),
+        _ => storage_error(e),
     }
-
-    Ok(StreamBody::new(ReaderStream::new(
-        tokio::fs::File::open(cache_path)
-            .await
-            .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?,
-    )))
 }
 
-async fn cache_head(
-    State(state): State<Arc<Args>>,
-    Path(hash): Path<String>,
-) -> Result<(), (StatusCode, String)> {
-    let cache_path = hash_to_file(&state.binary_root, &hash)?;
+/// Claims `hash` for a `PUT`, going by `If-None-Match: *`, the overwrite
+/// policy and other uploads of it in flight. Returns `None` if the upload
+/// should be acknowledged without storing it.
+///
+/// An upload that is still in flight counts as an existing entry, so only one
+/// upload of a key is ever written at a time.
+async fn begin_upload<'a>(
+    namespace: &'a Namespace,
+    overwrite: OverwritePolicy,
+    cache: Cache,
+    hash: &str,
+    headers: &HeaderMap,
+) -> Result<Option<UploadGuard<'a>>, (StatusCode, String)> {
+    let create_only = headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes().trim_ascii() == b"*");
+
+    let guard = namespace.uploads.begin(cache, hash);
+    let exists = match &guard {
+        None => true,
+        Some(_) if !create_only && overwrite == OverwritePolicy::Replace => false,
+        Some(_) => namespace
+            .storage(cache)
+            .head(hash)
+            .await
+            .map_err(storage_error)?
+            .is_some(),
+    };
+    if !exists {
+        return Ok(guard);
+    }
 
-    if !cache_path.exists() {
+    if create_only {
         return Err((
-            StatusCode::NOT_FOUND,
-            format!("{} does not exist", cache_path.display()),
+            StatusCode::PRECONDITION_FAILED,
+            format!("{} already exists", hash),
         ));
     }
+    match overwrite {
+        OverwritePolicy::Reject => Err((StatusCode::CONFLICT, format!("{} already exists", hash))),
+        // for replace, only reached while another upload is in flight, which
+        // is left to win
+        OverwritePolicy::Ignore | OverwritePolicy::Replace => Ok(None),
+    }
+}
 
-    Ok(())
+/// Sets `bytes` of the quota aside for an upload, before any of it is read.
+fn reserve_quota(namespace: &Namespace, bytes: u64) -> Result<Reservation, (StatusCode, String)> {
+    namespace.quota.reserve(bytes).ok_or_else(|| {
+        let used = namespace.quota.usage().total();
+        (
+            StatusCode::INSUFFICIENT_STORAGE,
+            format!(
+                "namespace {} is out of space: {} of its {} quota are used",
+                namespace.name,
+                human_bytes::human_bytes(used as f64),
+                human_bytes::human_bytes(namespace.quota.limit().unwrap_or_default() as f64)
+            ),
+        )
+    })
 }
 
-async fn write_stream_to_file(
-    path: &std::path::Path,
-    stream: impl Stream<Item = Result<Bytes, axum::Error>>,
-) -> Result<u64, io::Error> {
-    let mut file = BufWriter::new(File::create(path).await?);
+/// Stores an upload the caller holds the [`UploadGuard`] for, keeping the
+/// namespace's quota. Assets are checked against their digest, packages
+/// only by `validate`.
+///
+/// Without an `announced` length, the reservation grows with every chunk
+/// received, and the body is cut off as soon as a chunk no longer fits.
+async fn store_entry(
+    namespace: &Namespace,
+    metrics: &Metrics,
+    cache: Cache,
+    hash: &str,
+    mut body: ByteStream,
+    announced: Option<u64>,
+    validate: Option<Validate<'_>>,
+) -> Result<u64, (StatusCode, String)> {
+    let reservation = reserve_quota(namespace, announced.unwrap_or(0))?;
+    if announced.is_none() {
+        body = quota::cap(body, reservation.clone());
+    }
+
+    let storage = namespace.storage(cache);
+    // a replaced entry no longer counts once the upload is committed
+    let replaced = storage
+        .head(hash)
+        .await
+        .map_err(storage_error)?
+        .map_or(0, |meta| meta.size);
+    let expected_digest = match cache {
+        Cache::Binary => None,
+        Cache::Asset => Some(hash),
+    };
+    let bytes = storage
+        .put(hash, body, expected_digest, validate)
+        .await
+        .map_err(|e| match cache {
+            Cache::Binary => package_error(e),
+            Cache::Asset => upload_error(e),
+        })?;
+
+    namespace.quota.remove_stored(cache, replaced);
+    namespace.quota.add_stored(cache, bytes);
+    drop(reservation);
+    metrics
+        .bytes_written
+        .with_label_values(&[cache.name()])
+        .inc_by(bytes);
 
-    let body_with_io_error = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-    let body_reader = StreamReader::new(body_with_io_error);
-    futures::pin_mut!(body_reader);
+    info!(
+        "Wrote {} to {} for {} cache of {}",
+        human_bytes::human_bytes(bytes as f64),
+        hash,
+        cache.name(),
+        namespace.name
+    );
+    Ok(bytes)
+}
 
-    let bytes = tokio::io::copy(&mut body_reader, &mut file).await?;
+/// Stores a binary package if it is one and indexes it. Uploads and upstream
+/// pulls both go through here, holding the [`UploadGuard`] for `hash`.
+async fn commit_package(
+    namespace: &Namespace,
+    metrics: &Metrics,
+    hash: &str,
+    body: ByteStream,
+    announced: Option<u64>,
+) -> Result<u64, (StatusCode, String)> {
+    // the package is checked once received but before it is committed
+    let mut info = None;
+    let mut validate = |file: &mut std::fs::File| {
+        info = Some(package::read_info(&mut package::open(file.try_clone()?)?)?);
+        Ok(())
+    };
+    let bytes = store_entry(
+        namespace,
+        metrics,
+        Cache::Binary,
+        hash,
+        body,
+        announced,
+        Some(&mut validate),
+    )
+    .await?;
 
+    if let Err(e) = tokio::task::block_in_place(|| {
+        namespace
+            .index
+            .insert(hash, info.as_ref(), bytes, SystemTime::now())
+    }) {
+        warn!("Failed to index {}: {}", hash, e);
+    }
     Ok(bytes)
 }
 
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+fn package_error(e: io::Error) -> (StatusCode, String) {
+    if e.kind() == io::ErrorKind::InvalidData {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("not a vcpkg binary package: {}", e),
+        )
+    } else {
+        upload_error(e)
+    }
+}
+
+fn not_found(hash: &str) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("{} does not exist", hash))
+}
+
+/// Response header telling whether a binary package is pinned.
+const PINNED_HEADER: &str = "x-vcpkg-cache-pinned";
+
+/// Reports whether a binary package is pinned on its `GET` and `HEAD`
+/// responses.
+fn add_pinned_header(namespace: &Namespace, hash: &str, response: &mut Response) {
+    let pinned = if namespace.pins.contains(hash) {
+        "true"
+    } else {
+        "false"
+    };
+    response
+        .headers_mut()
+        .insert(PINNED_HEADER, HeaderValue::from_static(pinned));
+}
+
+/// Answers a `HEAD` for `meta`, which is everything a `GET` would send
+/// except the body.
+fn head_entry(meta: &ObjectMeta, headers: &HeaderMap) -> Response {
+    let mut response_headers = conditional::entry_headers(meta);
+    if conditional::is_not_modified(headers, meta) {
+        return (StatusCode::NOT_MODIFIED, response_headers).into_response();
+    }
+
+    response_headers.insert(header::CONTENT_LENGTH, meta.size.into());
+    (StatusCode::OK, response_headers).into_response()
+}
+
+/// Streams `meta` from `storage`, or only the part asked for with `Range`.
+async fn send_entry(
+    storage: &dyn Storage,
+    meta: &ObjectMeta,
+    headers: &HeaderMap,
+    served: IntCounter,
+) -> Result<Response, (StatusCode, String)> {
+    if conditional::is_not_modified(headers, meta) {
+        return Ok((StatusCode::NOT_MODIFIED, conditional::entry_headers(meta)).into_response());
+    }
+
+    let (status, range) = match conditional::select_range(headers, meta) {
+        Selection::Full => (StatusCode::OK, 0..meta.size),
+        Selection::Partial(range) => (StatusCode::PARTIAL_CONTENT, range),
+        Selection::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", meta.size))],
+            )
+                .into_response());
+        }
+    };
+
+    let stream = if status == StatusCode::PARTIAL_CONTENT {
+        storage.get_range(&meta.key, range.clone()).await
+    } else {
+        storage.get(&meta.key).await
+    };
+    let Some(stream) = stream.map_err(storage_error)? else {
+        return Err(not_found(&meta.key));
+    };
+
+    let mut response_headers = conditional::entry_headers(meta);
+    response_headers.insert(header::CONTENT_LENGTH, (range.end - range.start).into());
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, meta.size);
+        response_headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&content_range).unwrap(),
+        );
+    }
+
+    let body = StreamBody::new(stream.inspect_ok(move |chunk| served.inc_by(chunk.len() as u64)));
+    Ok((status, response_headers, body).into_response())
+}
+
+async fn cache_get(
+    State(state): State<Arc<AppState>>,
+    CurrentNamespace(namespace): CurrentNamespace,
+    Path(HashPath { hash }): Path<HashPath>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    validate_key(&hash)?;
+
+    let served = state.metrics.bytes_served.with_label_values(&["binary"]);
+    if let Some(meta) = namespace.binary.head(&hash).await.map_err(storage_error)? {
+        namespace.binary_access.record(&hash);
+        state.metrics.hits.with_label_values(&["binary"]).inc();
+        let mut response = send_entry(namespace.binary.as_ref(), &meta, &headers, served).await?;
+        add_pinned_header(&namespace, &hash, &mut response);
+        return Ok(response);
+    }
+
+    // ranges are not supported for upstream downloads, which are stored as
+    // they are sent
+    let mut stream = None;
+    if let Some(upstream) = &namespace.upstream {
+        // an unavailable upstream makes for an ordinary miss
+        stream = upstream
+            .get(&hash)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to get {} from upstream: {}", hash, e);
+                None
+            })
+            .map(|download| {
+                upstream::pull_through(
+                    namespace.clone(),
+                    state.metrics.clone(),
+                    hash.clone(),
+                    download,
+                )
+            });
+        if stream.is_some() {
+            state
+                .metrics
+                .upstream_hits
+                .with_label_values(&["binary"])
+                .inc();
+        }
+    }
+    let Some(stream) = stream else {
+        state
+            .metrics
+            .misses
+            .with_label_values(&["binary", "GET"])
+            .inc();
+        return Err(not_found(&hash));
+    };
+    namespace.binary_access.record(&hash);
+    state.metrics.hits.with_label_values(&["binary"]).inc();
+
+    Ok(
+        StreamBody::new(stream.inspect_ok(move |chunk| served.inc_by(chunk.len() as u64)))
+            .into_response(),
+    )
+}
+
+async fn cache_head(
+    State(state): State<Arc<AppState>>,
+    CurrentNamespace(namespace): CurrentNamespace,
+    Path(HashPath { hash }): Path<HashPath>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    validate_key(&hash)?;
+
+    if let Some(meta) = namespace.binary.head(&hash).await.map_err(storage_error)? {
+        let mut response = head_entry(&meta, &headers);
+        add_pinned_header(&namespace, &hash, &mut response);
+        return Ok(response);
+    }
+
+    let mut exists = false;
+    if let Some(upstream) = &namespace.upstream {
+        exists = upstream.head(&hash).await.unwrap_or_else(|e| {
+            warn!("Failed to look up {} upstream: {}", hash, e);
+            false
+        });
+    }
+    if !exists {
+        state
+            .metrics
+            .misses
+            .with_label_values(&["binary", "HEAD"])
+            .inc();
+        return Err(not_found(&hash));
+    }
+
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Reports how far behind each peer is.
+async fn replication_status(State(state): State<Arc<AppState>>) -> Json<Vec<PeerLag>> {
+    // only the default namespace is replicated
+    let replicator = &state.namespaces[DEFAULT_NAMESPACE].replicator;
+    Json(tokio::task::block_in_place(|| replicator.lag()))
+}
+
 async fn cache_put(
-    State(state): State<Arc<Args>>,
-    Path(hash): Path<String>,
+    State(state): State<Arc<AppState>>,
+    CurrentNamespace(namespace): CurrentNamespace,
+    Path(HashPath { hash }): Path<HashPath>,
+    headers: HeaderMap,
     body: BodyStream,
 ) -> Result<(), (StatusCode, String)> {
-    let cache_path = hash_to_file(&state.binary_root, &hash)?;
+    validate_key(&hash)?;
 
-    if !cache_path.parent().unwrap().exists() {
-        fs::create_dir(cache_path.parent().unwrap())
-            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    }
+    let Some(_upload) =
+        begin_upload(&namespace, state.overwrite, Cache::Binary, &hash, &headers).await?
+    else {
+        info!(
+            "{} already exists in binary cache of {}, ignoring upload",
+            hash, namespace.name
+        );
+        return Ok(());
+    };
 
-    let bytes = write_stream_to_file(&cache_path, body)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    commit_package(
+        &namespace,
+        &state.metrics,
+        &hash,
+        body.map_err(io::Error::other).boxed(),
+        content_length(&headers),
+    )
+    .await?;
 
-    info!(
-        "Wrote {} to {} for binary cache",
-        human_bytes::human_bytes(bytes as f64),
-        cache_path.display()
-    );
+    if !headers.contains_key(replication::REPLICATED_HEADER) {
+        if let Err(e) = tokio::task::block_in_place(|| namespace.replicator.enqueue(&hash)) {
+            warn!("Failed to queue {} for replication: {}", hash, e);
+        }
+    }
 
     Ok(())
 }
 
+async fn asset_get(
+    State(state): State<Arc<AppState>>,
+    CurrentNamespace(namespace): CurrentNamespace,
+    Path(HashPath { hash }): Path<HashPath>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    validate_key(&hash)?;
+
+    let Some(meta) = namespace.asset.head(&hash).await.map_err(storage_error)? else {
+        state
+            .metrics
+            .misses
+            .with_label_values(&["asset", "GET"])
+            .inc();
+        return Err(not_found(&hash));
+    };
+    namespace.asset_access.record(&hash);
+    state.metrics.hits.with_label_values(&["asset"]).inc();
+
+    let served = state.metrics.bytes_served.with_label_values(&["asset"]);
+    send_entry(namespace.asset.as_ref(), &meta, &headers, served).await
+}
+
+async fn asset_head(
+    State(state): State<Arc<AppState>>,
+    CurrentNamespace(namespace): CurrentNamespace,
+    Path(HashPath { hash }): Path<HashPath>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    validate_key(&hash)?;
+
+    let Some(meta) = namespace.asset.head(&hash).await.map_err(storage_error)? else {
+        state
+            .metrics
+            .misses
+            .with_label_values(&["asset", "HEAD"])
+            .inc();
+        return Err(not_found(&hash));
+    };
+
+    Ok(head_entry(&meta, &headers))
+}
+
 async fn asset_put(
-    State(state): State<Arc<Args>>,
-    Path(hash): Path<String>,
+    State(state): State<Arc<AppState>>,
+    CurrentNamespace(namespace): CurrentNamespace,
+    Path(HashPath { hash }): Path<HashPath>,
+    headers: HeaderMap,
     body: BodyStream,
 ) -> Result<(), (StatusCode, String)> {
-    let path = state.asset_root.join(hash);
+    validate_key(&hash)?;
 
-    let bytes = write_stream_to_file(&path, body)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let Some(_upload) =
+        begin_upload(&namespace, state.overwrite, Cache::Asset, &hash, &headers).await?
+    else {
+        info!(
+            "{} already exists in asset cache of {}, ignoring upload",
+            hash, namespace.name
+        );
+        return Ok(());
+    };
 
-    info!(
-        "Wrote {} to {} for asset cache",
-        human_bytes::human_bytes(bytes as f64),
-        path.display()
-    );
+    store_entry(
+        &namespace,
+        &state.metrics,
+        Cache::Asset,
+        &hash,
+        body.map_err(io::Error::other).boxed(),
+        content_length(&headers),
+        None,
+    )
+    .await?;
 
     Ok(())
 }
+
+#[derive(serde::Deserialize)]
+struct PackagesQuery {
+    port: Option<String>,
+    triplet: Option<String>,
+}
+
+/// Lists indexed binary packages, optionally only those for one port and/or
+/// triplet.
+async fn packages_get(
+    CurrentNamespace(namespace): CurrentNamespace,
+    Query(query): Query<PackagesQuery>,
+) -> Result<Json<Vec<PackageRecord>>, (StatusCode, String)> {
+    let packages = tokio::task::block_in_place(|| {
+        namespace
+            .index
+            .query(query.port.as_deref(), query.triplet.as_deref())
+    })
+    .map_err(storage_error)?;
+
+    Ok(Json(packages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn rejected(key: &str) -> bool {
+        validate_key(key).is_err_and(|(status, _)| status == StatusCode::BAD_REQUEST)
+    }
+
+    #[test]
+    fn accepts_both_digest_lengths() {
+        assert!(validate_key(SHA256).is_ok());
+        assert!(validate_key(&SHA256.repeat(2)).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_lengths() {
+        assert!(rejected(""));
+        assert!(rejected("abc123"));
+        assert!(rejected(&SHA256[1..]));
+        assert!(rejected(&format!("{}0", SHA256)));
+    }
+
+    #[test]
+    fn rejects_uppercase_hex() {
+        assert!(rejected(&SHA256.to_uppercase()));
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        // padded to a valid length, so only the characters are at fault
+        let dots = format!("../../{}", &SHA256[6..]);
+        assert_eq!(dots.len(), 64);
+        assert!(rejected(&dots));
+
+        // what axum hands the handler for `..%2F..%2Fetc%2Fpasswd`
+        let decoded = format!("../../etc/passwd{}", &SHA256[16..]);
+        assert_eq!(decoded.len(), 64);
+        assert!(rejected(&decoded));
+
+        let backslash = format!("..\\{}", &SHA256[3..]);
+        assert!(rejected(&backslash));
+    }
+
+    #[test]
+    fn rejects_multi_byte_utf8() {
+        // "é" is two bytes, so this is 64 bytes long
+        let key = format!("é{}", &SHA256[2..]);
+        assert_eq!(key.len(), 64);
+        assert!(rejected(&key));
+    }
+
+    /// A zip with the members [`package::open`] looks for.
+    fn package() -> Vec<u8> {
+        use std::io::Write;
+
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, contents) in [
+            (
+                "CONTROL",
+                "Package: zlib\nVersion: 1.3\nArchitecture: x64-linux\n",
+            ),
+            ("share/zlib/vcpkg_abi_info.txt", "cmake 3.27.0\n"),
+        ] {
+            zip.start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// Serves a cache with its roots in `root` on an ephemeral port.
+    async fn spawn_server(root: &std::path::Path, args: &[&str]) -> String {
+        let binary_root = root.join("binary");
+        let asset_root = root.join("asset");
+        let cli = Cli::parse_from(
+            [
+                "vcpkg-http-binary-cache",
+                "--binary-root",
+                binary_root.to_str().unwrap(),
+                "--asset-root",
+                asset_root.to_str().unwrap(),
+            ]
+            .iter()
+            .chain(args),
+        );
+        let app = app(&Config::load(&cli.serve).unwrap()).await.unwrap();
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pulls_misses_through_from_upstream() {
+        let root = tempfile::tempdir().unwrap();
+        let upstream = spawn_server(&root.path().join("upstream"), &[]).await;
+        let local = spawn_server(&root.path().join("local"), &["--upstream", &upstream]).await;
+        let client = reqwest::Client::new();
+        let package = package();
+
+        let status = client
+            .put(format!("{}/cache/{}", upstream, SHA256))
+            .body(package.clone())
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, StatusCode::OK);
+
+        // HEAD misses are answered by the upstream without pulling anything
+        let head = |hash: &str| client.head(format!("{}/cache/{}", local, hash)).send();
+        assert_eq!(head(SHA256).await.unwrap().status(), StatusCode::OK);
+        let unknown = SHA256.replace('0', "f");
+        assert_eq!(
+            head(&unknown).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+        let packages = || async {
+            let response = client
+                .get(format!("{}/api/packages", local))
+                .send()
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&response.bytes().await.unwrap()).unwrap()
+        };
+        assert_eq!(packages().await, serde_json::json!([]));
+
+        let response = client
+            .get(format!("{}/cache/{}", local, SHA256))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), package);
+
+        // stored in the background once the download finished
+        let mut indexed = packages().await;
+        for _ in 0..50 {
+            if indexed != serde_json::json!([]) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            indexed = packages().await;
+        }
+        assert_eq!(indexed[0]["abi"], SHA256);
+        assert_eq!(indexed[0]["port"], "zlib");
+        assert_eq!(indexed[0]["size"], package.len());
+
+        let stored = FsStorage::new(root.path().join("local/binary"), Layout::Binary)
+            .head(SHA256)
+            .await
+            .unwrap()
+            .expect("pulled package is stored");
+        assert_eq!(stored.size, package.len() as u64);
+    }
+}
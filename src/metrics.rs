@@ -0,0 +1,162 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// Prometheus metrics exported on `/metrics`.
+///
+/// Everything about the caches themselves has a `cache` label that is either
+/// `binary` or `asset`. What the eviction task reports is also labeled by
+/// `namespace`.
+pub struct Metrics {
+    registry: Registry,
+    /// Successful `GET`s
+    pub hits: IntCounterVec,
+    /// `GET`s and `HEAD`s for keys that do not exist, labeled by `method`
+    pub misses: IntCounterVec,
+    /// Local misses that were served from the upstream cache
+    pub upstream_hits: IntCounterVec,
+    pub bytes_served: IntCounterVec,
+    pub bytes_written: IntCounterVec,
+    pub request_duration: HistogramVec,
+    /// Updated periodically by the eviction task
+    pub storage_bytes: IntGaugeVec,
+    pub storage_entries: IntGaugeVec,
+    /// Entries removed by expiry or eviction, labeled by `reason`
+    pub deleted: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let hits = IntCounterVec::new(
+            Opts::new("vcpkg_cache_hits_total", "Successful downloads"),
+            &["cache"],
+        )
+        .unwrap();
+        let misses = IntCounterVec::new(
+            Opts::new("vcpkg_cache_misses_total", "Lookups of missing keys"),
+            &["cache", "method"],
+        )
+        .unwrap();
+        let upstream_hits = IntCounterVec::new(
+            Opts::new(
+                "vcpkg_cache_upstream_hits_total",
+                "Local misses served from the upstream cache",
+            ),
+            &["cache"],
+        )
+        .unwrap();
+        let bytes_served = IntCounterVec::new(
+            Opts::new("vcpkg_cache_served_bytes_total", "Bytes sent to clients"),
+            &["cache"],
+        )
+        .unwrap();
+        let bytes_written = IntCounterVec::new(
+            Opts::new(
+                "vcpkg_cache_written_bytes_total",
+                "Bytes of committed uploads",
+            ),
+            &["cache"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "vcpkg_cache_request_duration_seconds",
+                "Time until the response headers were sent",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let storage_bytes = IntGaugeVec::new(
+            Opts::new("vcpkg_cache_storage_bytes", "Total size of stored entries"),
+            &["namespace", "cache"],
+        )
+        .unwrap();
+        let storage_entries = IntGaugeVec::new(
+            Opts::new("vcpkg_cache_storage_entries", "Number of stored entries"),
+            &["namespace", "cache"],
+        )
+        .unwrap();
+        let deleted = IntCounterVec::new(
+            Opts::new(
+                "vcpkg_cache_deleted_total",
+                "Entries removed by expiry (idle, age) or eviction (size)",
+            ),
+            &["namespace", "cache", "reason"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(hits.clone())).unwrap();
+        registry.register(Box::new(misses.clone())).unwrap();
+        registry.register(Box::new(upstream_hits.clone())).unwrap();
+        registry.register(Box::new(bytes_served.clone())).unwrap();
+        registry.register(Box::new(bytes_written.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(storage_bytes.clone())).unwrap();
+        registry
+            .register(Box::new(storage_entries.clone()))
+            .unwrap();
+        registry.register(Box::new(deleted.clone())).unwrap();
+
+        Metrics {
+            registry,
+            hits,
+            misses,
+            upstream_hits,
+            bytes_served,
+            bytes_written,
+            request_duration,
+            storage_bytes,
+            storage_entries,
+            deleted,
+        }
+    }
+}
+
+/// Handler for `/metrics` in the Prometheus text format.
+pub async fn metrics_get(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&metrics.registry.gather(), &mut buffer) {
+        Ok(()) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+            buffer,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Middleware recording how long each request took.
+pub async fn track_requests<B>(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_owned(), |path| path.as_str().to_owned());
+
+    let response = next.run(request).await;
+
+    metrics
+        .request_duration
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
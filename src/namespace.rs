@@ -0,0 +1,218 @@
+//! Namespaces keep the packages of teams sharing one server apart. Each has
+//! its own roots, tokens and limits and is served under `/<name>/`, while the
+//! un-prefixed routes serve the default namespace.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use axum::{
+    extract::{rejection::PathRejection, FromRequestParts, Path},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+
+use crate::{
+    access::AccessLog,
+    admin::Caches,
+    auth::Tokens,
+    config::Config,
+    eviction,
+    index::{self, Index},
+    load_pins,
+    metrics::Metrics,
+    open_caches,
+    pins::Pins,
+    quota::Quota,
+    replication::Replicator,
+    storage::{FsStorage, Layout, Storage},
+    uploads::Uploads,
+    upstream::Upstream,
+    AppState,
+};
+
+/// The namespace of the routes without a `/<name>` prefix.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// One of the two caches every namespace has.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Cache {
+    Binary,
+    Asset,
+}
+
+impl Cache {
+    pub fn name(self) -> &'static str {
+        match self {
+            Cache::Binary => "binary",
+            Cache::Asset => "asset",
+        }
+    }
+}
+
+/// The caches of one namespace and their bookkeeping.
+pub struct Namespace {
+    pub name: String,
+    pub binary: Arc<dyn Storage>,
+    pub asset: Arc<dyn Storage>,
+    pub binary_access: Arc<AccessLog>,
+    pub asset_access: Arc<AccessLog>,
+    pub pins: Arc<Pins>,
+    pub index: Arc<Index>,
+    pub tokens: Tokens,
+    pub quota: Quota,
+    /// Only ever set for the default namespace
+    pub upstream: Option<Upstream>,
+    pub replicator: Replicator,
+    pub uploads: Uploads,
+}
+
+impl Namespace {
+    /// Opens namespace `name` with `config` from [`Config::for_namespace`]
+    /// and starts its eviction, index and replication tasks.
+    pub async fn start(
+        name: &str,
+        config: &Config,
+        metrics: &Arc<Metrics>,
+    ) -> anyhow::Result<Arc<Namespace>> {
+        for root in [&config.binary_root, &config.asset_root] {
+            std::fs::create_dir_all(root)
+                .with_context(|| format!("creating {}", root.display()))?;
+        }
+        if config.s3_bucket.is_none() {
+            for (root, layout) in [
+                (&config.binary_root, Layout::Binary),
+                (&config.asset_root, Layout::Asset),
+            ] {
+                // nothing else may be writing to the roots yet
+                if let Err(e) =
+                    FsStorage::new(root.clone(), layout).sweep_staging_files(Duration::ZERO)
+                {
+                    warn!("Failed to sweep staging files in {}: {}", root.display(), e);
+                }
+            }
+        }
+        let Caches { binary, asset } = open_caches(config, name)?;
+
+        let binary_access = Arc::new(
+            AccessLog::load(&config.binary_root)
+                .with_context(|| format!("loading binary cache access log of {}", name))?,
+        );
+        let asset_access = Arc::new(
+            AccessLog::load(&config.asset_root)
+                .with_context(|| format!("loading asset cache access log of {}", name))?,
+        );
+
+        let pins = Arc::new(load_pins(config, name).await?);
+
+        let index = Arc::new(
+            Index::open(&config.binary_root)
+                .with_context(|| format!("opening package index of {}", name))?,
+        );
+        tokio::spawn(index::index_task(binary.clone(), index.clone()));
+
+        let tokens = config.tokens()?;
+        if tokens.is_empty() {
+            warn!(
+                "No tokens configured for namespace {}, anyone can read and write it",
+                name
+            );
+        }
+
+        let replicator = Replicator::start(
+            &config.binary_root,
+            &config.peers,
+            config.peer_token.clone(),
+            binary.clone(),
+        )
+        .context("setting up replication queues")?;
+
+        let namespace = Arc::new(Namespace {
+            name: name.to_owned(),
+            binary,
+            asset,
+            binary_access,
+            asset_access,
+            pins,
+            index,
+            tokens,
+            quota: Quota::new(config.quota),
+            upstream: config
+                .upstream
+                .as_deref()
+                .map(|url| Upstream::new(url, config.upstream_token.clone())),
+            replicator,
+            uploads: Uploads::default(),
+        });
+
+        tokio::spawn(eviction::eviction_task(
+            namespace.clone(),
+            Cache::Binary,
+            config.binary_limits(),
+            metrics.clone(),
+        ));
+        tokio::spawn(eviction::eviction_task(
+            namespace.clone(),
+            Cache::Asset,
+            config.asset_limits(),
+            metrics.clone(),
+        ));
+
+        Ok(namespace)
+    }
+
+    pub fn storage(&self, cache: Cache) -> &Arc<dyn Storage> {
+        match cache {
+            Cache::Binary => &self.binary,
+            Cache::Asset => &self.asset,
+        }
+    }
+
+    pub fn access_log(&self, cache: Cache) -> &Arc<AccessLog> {
+        match cache {
+            Cache::Binary => &self.binary_access,
+            Cache::Asset => &self.asset_access,
+        }
+    }
+
+    /// The pins of `cache`, if it supports them; only binary packages do.
+    pub fn pins(&self, cache: Cache) -> Option<&Arc<Pins>> {
+        match cache {
+            Cache::Binary => Some(&self.pins),
+            Cache::Asset => None,
+        }
+    }
+}
+
+/// Extracts the namespace named by the `:namespace` route parameter, or the
+/// default one for routes without it.
+pub struct CurrentNamespace(pub Arc<Namespace>);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for CurrentNamespace {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let params = match Path::<HashMap<String, String>>::from_request_parts(parts, state).await {
+            Ok(Path(params)) => params,
+            Err(PathRejection::MissingPathParams(_)) => HashMap::new(),
+            Err(rejection) => return Err(rejection.into_response()),
+        };
+
+        let name = params
+            .get("namespace")
+            .map_or(DEFAULT_NAMESPACE, String::as_str);
+        match state.namespaces.get(name) {
+            Some(namespace) => Ok(CurrentNamespace(namespace.clone())),
+            None => Err((
+                StatusCode::NOT_FOUND,
+                format!("namespace {} does not exist", name),
+            )
+                .into_response()),
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Checks that binary packages look like something vcpkg can restore.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+};
+
+use zip::ZipArchive;
+
+/// What a package is a build of, as recorded inside it by vcpkg.
+#[derive(Clone, Debug, Default)]
+pub struct PackageInfo {
+    pub port: String,
+    pub version: Option<String>,
+    pub triplet: Option<String>,
+    pub features: Vec<String>,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Opens `file` as a zip and checks that it has the layout vcpkg writes:
+/// `share/<port>/vcpkg_abi_info.txt`, plus either a top-level `CONTROL` or
+/// `share/<port>/vcpkg.spdx.json`.
+///
+/// Only the central directory is read, member contents are not checked.
+/// Fails with [`io::ErrorKind::InvalidData`] if the package is malformed.
+pub fn open(file: File) -> io::Result<ZipArchive<File>> {
+    let archive = ZipArchive::new(file).map_err(|e| match e {
+        zip::result::ZipError::Io(e) => e,
+        e => invalid(format!("not a valid zip file: {}", e)),
+    })?;
+
+    let names = file_names(&archive);
+    let ports = ports(&names);
+
+    if ports.is_empty() {
+        return Err(invalid("missing share/<port>/vcpkg_abi_info.txt"));
+    }
+
+    let has_control = names.iter().any(|name| name == "CONTROL");
+    let has_spdx = ports
+        .iter()
+        .any(|port| names.contains(&format!("share/{}/vcpkg.spdx.json", port)));
+    if !has_control && !has_spdx {
+        return Err(invalid("missing CONTROL or share/<port>/vcpkg.spdx.json"));
+    }
+
+    Ok(archive)
+}
+
+/// Member names with `/` separators, whatever the zip was made with.
+fn file_names(archive: &ZipArchive<File>) -> Vec<String> {
+    archive.file_names().map(|n| n.replace('\\', "/")).collect()
+}
+
+/// Ports that have a `share/<port>/vcpkg_abi_info.txt`.
+fn ports(names: &[String]) -> Vec<&str> {
+    names
+        .iter()
+        .filter_map(|name| {
+            name.strip_prefix("share/")?
+                .strip_suffix("/vcpkg_abi_info.txt")
+                .filter(|port| !port.is_empty() && !port.contains('/'))
+        })
+        .collect()
+}
+
+fn read_member(archive: &mut ZipArchive<File>, name: &str) -> io::Result<Option<String>> {
+    let mut file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(invalid(e.to_string())),
+    };
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(Some(contents))
+}
+
+/// Reads the port, version, triplet and features of a package opened with
+/// [`open`].
+///
+/// The version and triplet come from `CONTROL`, or from `vcpkg.spdx.json` for
+/// packages without one. Features come from `vcpkg_abi_info.txt`.
+pub fn read_info(archive: &mut ZipArchive<File>) -> io::Result<PackageInfo> {
+    let names = file_names(archive);
+    let Some(port) = ports(&names).first().map(|port| port.to_string()) else {
+        return Err(invalid("missing share/<port>/vcpkg_abi_info.txt"));
+    };
+    let mut info = PackageInfo {
+        port,
+        ..PackageInfo::default()
+    };
+
+    let abi_info = format!("share/{}/vcpkg_abi_info.txt", info.port);
+    if let Some(abi_info) = read_member(archive, &abi_info)? {
+        for line in abi_info.lines() {
+            if let Some(features) = line.strip_prefix("features ") {
+                info.features = features.split(';').map(str::to_owned).collect();
+            }
+        }
+    }
+
+    if let Some(control) = read_member(archive, "CONTROL")? {
+        parse_control(&control, &mut info);
+    } else if let Some(spdx) =
+        read_member(archive, &format!("share/{}/vcpkg.spdx.json", info.port))?
+    {
+        parse_spdx(&spdx, &mut info);
+    }
+
+    Ok(info)
+}
+
+/// `CONTROL` is a list of `Field: value` paragraphs; the first describes the
+/// package itself and each following one an installed feature.
+fn parse_control(control: &str, info: &mut PackageInfo) {
+    let Some(paragraph) = control.split("\n\n").next() else {
+        return;
+    };
+
+    let mut port_version = None;
+    for line in paragraph.lines() {
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_owned();
+        match field.trim() {
+            "Version" => info.version = Some(value),
+            "Port-Version" if value != "0" => port_version = Some(value),
+            "Architecture" => info.triplet = Some(value),
+            _ => {}
+        }
+    }
+
+    if let (Some(version), Some(port_version)) = (&mut info.version, port_version) {
+        *version = format!("{}#{}", version, port_version);
+    }
+}
+
+/// The SPDX document is named `<port>:<triplet>@<version> <abi>`.
+fn parse_spdx(spdx: &str, info: &mut PackageInfo) {
+    let Ok(document) = serde_json::from_str::<serde_json::Value>(spdx) else {
+        return;
+    };
+    let Some((spec, _abi)) = document["name"].as_str().and_then(|n| n.split_once(' ')) else {
+        return;
+    };
+    let Some((spec, version)) = spec.split_once('@') else {
+        return;
+    };
+
+    info.version = Some(version.to_owned());
+    if let Some((_port, triplet)) = spec.split_once(':') {
+        info.triplet = Some(triplet.to_owned());
+    }
+}
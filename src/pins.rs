@@ -0,0 +1,178 @@
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use futures::StreamExt;
+
+use crate::storage::Storage;
+
+/// File under the binary root that pins are persisted to.
+const PINS_FILE: &str = ".pins";
+
+/// Binary packages that expiry, eviction and purges must never delete, such as
+/// those used by shipped releases.
+///
+/// Persisted on every change, either to `<binary_root>/.pins`, one hash per
+/// line, or as one empty object per pin when entries live in S3, so that every
+/// server sharing the bucket honors them.
+pub struct Pins {
+    backing: Backing,
+    hashes: Mutex<BTreeSet<String>>,
+}
+
+enum Backing {
+    File(PathBuf),
+    Storage(Arc<dyn Storage>),
+}
+
+impl Pins {
+    /// Loads the pins for `root`, starting empty if there are none yet.
+    pub fn load(root: &Path) -> io::Result<Pins> {
+        let path = root.join(PINS_FILE);
+        let hashes = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Pins {
+            backing: Backing::File(path),
+            hashes: Mutex::new(hashes),
+        })
+    }
+
+    /// Loads the pins kept as objects in `storage`, which other servers may
+    /// share.
+    pub async fn load_shared(storage: Arc<dyn Storage>) -> io::Result<Pins> {
+        let pins = Pins {
+            backing: Backing::Storage(storage),
+            hashes: Mutex::default(),
+        };
+        pins.refresh().await?;
+        Ok(pins)
+    }
+
+    /// Picks up what other servers sharing the storage pinned or unpinned;
+    /// pins kept in a file only ever change here.
+    pub async fn refresh(&self) -> io::Result<()> {
+        if let Backing::Storage(storage) = &self.backing {
+            let hashes = storage
+                .list()
+                .await?
+                .into_iter()
+                .map(|object| object.key)
+                .collect();
+            *self.hashes.lock().unwrap() = hashes;
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.hashes.lock().unwrap().contains(hash)
+    }
+
+    /// Returns every pinned hash, sorted.
+    pub fn list(&self) -> Vec<String> {
+        self.hashes.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Pins `hash`, returning whether it was not pinned before.
+    pub async fn pin(&self, hash: &str) -> io::Result<bool> {
+        match &self.backing {
+            Backing::File(path) => tokio::task::block_in_place(|| {
+                let mut hashes = self.hashes.lock().unwrap();
+                if !hashes.insert(hash.to_owned()) {
+                    return Ok(false);
+                }
+                save(path, &hashes)?;
+                Ok(true)
+            }),
+            Backing::Storage(storage) => {
+                if self.contains(hash) {
+                    return Ok(false);
+                }
+                storage
+                    .put(hash, futures::stream::empty().boxed(), None, None)
+                    .await?;
+                Ok(self.hashes.lock().unwrap().insert(hash.to_owned()))
+            }
+        }
+    }
+
+    /// Unpins `hash`, returning whether it was pinned.
+    pub async fn unpin(&self, hash: &str) -> io::Result<bool> {
+        match &self.backing {
+            Backing::File(path) => tokio::task::block_in_place(|| {
+                let mut hashes = self.hashes.lock().unwrap();
+                if !hashes.remove(hash) {
+                    return Ok(false);
+                }
+                save(path, &hashes)?;
+                Ok(true)
+            }),
+            Backing::Storage(storage) => {
+                let deleted = storage.delete(hash).await?;
+                let removed = self.hashes.lock().unwrap().remove(hash);
+                Ok(deleted || removed)
+            }
+        }
+    }
+}
+
+/// Replaces the pins file atomically, so a crash never loses every pin.
+fn save(path: &Path, hashes: &BTreeSet<String>) -> io::Result<()> {
+    let mut contents = String::new();
+    for hash in hashes {
+        contents += hash;
+        contents.push('\n');
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let staging = path.with_extension("tmp");
+    fs::write(&staging, contents)?;
+    fs::File::open(&staging)?.sync_all()?;
+    fs::rename(&staging, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::memory::InMemory;
+
+    use super::*;
+    use crate::storage::{Layout, S3Storage};
+
+    const SHA256: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[tokio::test]
+    async fn shared_pins_reach_other_servers() {
+        let store = Arc::new(InMemory::new());
+        let open = || async {
+            let storage = S3Storage::with_store(store.clone(), "pins", Layout::Asset);
+            Pins::load_shared(Arc::new(storage)).await.unwrap()
+        };
+        let here = open().await;
+        let there = open().await;
+
+        assert!(here.pin(SHA256).await.unwrap());
+        assert!(!here.pin(SHA256).await.unwrap());
+        assert!(!there.contains(SHA256));
+        there.refresh().await.unwrap();
+        assert!(there.contains(SHA256));
+        assert!(open().await.contains(SHA256));
+
+        assert!(there.unpin(SHA256).await.unwrap());
+        here.refresh().await.unwrap();
+        assert!(here.list().is_empty());
+        assert!(!here.unpin(SHA256).await.unwrap());
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use futures::{future, StreamExt, TryStreamExt};
+use serde::Serialize;
+
+use crate::{namespace::Cache, storage::ByteStream};
+
+/// How many bytes a namespace may store, binary packages and assets together,
+/// and how many it does.
+///
+/// The stored totals are set whenever the eviction task lists a cache and
+/// adjusted by uploads and deletions in between, so they can briefly lag
+/// behind after expiry or pull-through downloads.
+pub struct Quota {
+    limit: Option<u64>,
+    usage: Arc<Mutex<Usage>>,
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    pub binary: u64,
+    pub asset: u64,
+    /// Announced sizes of uploads in flight, or what they received so far
+    pub reserved: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.binary + self.asset + self.reserved
+    }
+
+    fn stored(&mut self, cache: Cache) -> &mut u64 {
+        match cache {
+            Cache::Binary => &mut self.binary,
+            Cache::Asset => &mut self.asset,
+        }
+    }
+}
+
+/// Space set aside for an upload until the last clone is dropped.
+#[derive(Clone)]
+pub struct Reservation(Arc<Reserved>);
+
+struct Reserved {
+    limit: Option<u64>,
+    usage: Arc<Mutex<Usage>>,
+    bytes: Mutex<u64>,
+}
+
+impl Quota {
+    pub fn new(limit: Option<u64>) -> Quota {
+        Quota {
+            limit,
+            usage: Arc::default(),
+        }
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    pub fn usage(&self) -> Usage {
+        *self.usage.lock().unwrap()
+    }
+
+    /// Replaces the total of `cache` with what listing it found.
+    pub fn set_stored(&self, cache: Cache, bytes: u64) {
+        *self.usage.lock().unwrap().stored(cache) = bytes;
+    }
+
+    pub fn add_stored(&self, cache: Cache, bytes: u64) {
+        *self.usage.lock().unwrap().stored(cache) += bytes;
+    }
+
+    pub fn remove_stored(&self, cache: Cache, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        let stored = usage.stored(cache);
+        *stored = stored.saturating_sub(bytes);
+    }
+
+    /// Sets `bytes` aside for an upload, or returns `None` if the quota is
+    /// already used up or would be exceeded by them.
+    pub fn reserve(&self, bytes: u64) -> Option<Reservation> {
+        if self
+            .limit
+            .is_some_and(|limit| self.usage().total() >= limit)
+        {
+            return None;
+        }
+
+        let reservation = Reservation(Arc::new(Reserved {
+            limit: self.limit,
+            usage: self.usage.clone(),
+            bytes: Mutex::new(0),
+        }));
+        reservation.grow(bytes).then_some(reservation)
+    }
+}
+
+impl Reservation {
+    /// Sets `bytes` more aside, or returns `false` if they do not fit.
+    pub fn grow(&self, bytes: u64) -> bool {
+        let mut usage = self.0.usage.lock().unwrap();
+        if let Some(limit) = self.0.limit {
+            if usage.total().saturating_add(bytes) > limit {
+                return false;
+            }
+        }
+        usage.reserved += bytes;
+        *self.0.bytes.lock().unwrap() += bytes;
+        true
+    }
+}
+
+impl Drop for Reserved {
+    fn drop(&mut self) {
+        self.usage.lock().unwrap().reserved -= *self.bytes.get_mut().unwrap();
+    }
+}
+
+/// Grows `reservation` by every chunk of `stream` as it arrives, for uploads
+/// whose size is not known up front, and fails the stream with
+/// [`io::ErrorKind::StorageFull`] as soon as a chunk does not fit.
+pub fn cap(stream: ByteStream, reservation: Reservation) -> ByteStream {
+    let mut received = 0u64;
+    stream
+        .and_then(move |chunk| {
+            future::ready(if reservation.grow(chunk.len() as u64) {
+                received += chunk.len() as u64;
+                Ok(chunk)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    format!(
+                        "upload does not fit into the quota after {}",
+                        human_bytes::human_bytes(received as f64)
+                    ),
+                ))
+            })
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+    use futures::stream;
+
+    use super::*;
+
+    fn chunks(sizes: &[usize]) -> ByteStream {
+        let chunks: Vec<_> = sizes.iter().map(|&n| Ok(Bytes::from(vec![0; n]))).collect();
+        stream::iter(chunks).boxed()
+    }
+
+    #[test]
+    fn reservations_share_the_limit() {
+        let quota = Quota::new(Some(1000));
+        let first = quota.reserve(0).unwrap();
+        let second = quota.reserve(0).unwrap();
+        assert!(first.grow(800));
+        assert!(!second.grow(800));
+        assert!(second.grow(200));
+        assert!(quota.reserve(0).is_none());
+
+        drop(first);
+        assert_eq!(quota.usage().reserved, 200);
+        // clones keep the space until the last one is dropped
+        let clone = second.clone();
+        drop(second);
+        assert_eq!(quota.usage().reserved, 200);
+        drop(clone);
+        assert_eq!(quota.usage().reserved, 0);
+    }
+
+    #[tokio::test]
+    async fn cap_fails_once_a_chunk_does_not_fit() {
+        let quota = Quota::new(Some(1000));
+        let other = quota.reserve(400).unwrap();
+
+        let capped = cap(chunks(&[300, 300]), quota.reserve(0).unwrap());
+        assert_eq!(capped.try_collect::<Vec<_>>().await.unwrap().len(), 2);
+
+        let mut capped = cap(chunks(&[300, 300, 300]), quota.reserve(0).unwrap());
+        assert!(capped.try_next().await.unwrap().is_some());
+        assert!(capped.try_next().await.unwrap().is_some());
+        let err = capped.try_next().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        drop(capped);
+        drop(other);
+        assert_eq!(quota.usage().reserved, 0);
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use futures::{SinkExt, StreamExt};
+use reqwest::StatusCode;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::storage::Storage;
+
+/// Directory under the binary root holding one queue directory per peer.
+const QUEUE_DIR: &str = ".replication";
+
+/// Header marking uploads made by replication, so the receiving server does
+/// not replicate them again (which would loop between mutual peers).
+pub const REPLICATED_HEADER: &str = "x-vcpkg-cache-replicated";
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Chunks buffered between storage and the request body.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// How often an idle peer checks its queue even without being notified.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a push may go without sending anything or getting an answer.
+/// Pushes as a whole may take as long as big packages need.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Pushes committed binary packages to peer caches in the background.
+///
+/// Every pending push is an empty file named after the hash in the peer's
+/// queue directory, so the queue survives restarts and peer outages. Pushes
+/// are retried with exponential backoff until they succeed, unless the peer
+/// refuses the package with a 4xx that retrying cannot fix.
+pub struct Replicator {
+    peers: Vec<Arc<Peer>>,
+}
+
+struct Peer {
+    url: String,
+    queue_dir: PathBuf,
+    /// Where packages the peer refused for good are moved, next to `queue_dir`
+    rejected_dir: PathBuf,
+    notify: Notify,
+    status: Mutex<PeerStatus>,
+}
+
+#[derive(Default)]
+struct PeerStatus {
+    last_success: Option<SystemTime>,
+    last_error: Option<String>,
+    consecutive_failures: u32,
+}
+
+/// How far behind a peer is, as reported on `/status/replication`.
+#[derive(Serialize)]
+pub struct PeerLag {
+    pub url: String,
+    pub pending: usize,
+    /// Packages the peer refused and that are no longer retried
+    pub rejected: usize,
+    /// Age of the oldest package not yet pushed
+    pub lag_seconds: u64,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// Seconds since the last successful push
+    pub last_success_seconds_ago: Option<u64>,
+}
+
+impl Replicator {
+    /// Sets up queues under `binary_root` and starts one worker per peer.
+    pub fn start(
+        binary_root: &Path,
+        peer_urls: &[String],
+        token: Option<String>,
+        storage: Arc<dyn Storage>,
+    ) -> io::Result<Replicator> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .map_err(io::Error::other)?;
+
+        let mut peers = Vec::new();
+        for url in peer_urls {
+            let url = url.trim_end_matches('/').to_owned();
+
+            // the URL itself may not be a valid file name
+            let peer_id = format!("{:x}", Sha256::digest(url.as_bytes()));
+            let queue_dir = binary_root.join(QUEUE_DIR).join(&peer_id[..16]);
+            fs::create_dir_all(&queue_dir)?;
+            let rejected_dir = queue_dir.with_extension("rejected");
+
+            let peer = Arc::new(Peer {
+                url,
+                queue_dir,
+                rejected_dir,
+                notify: Notify::new(),
+                status: Mutex::default(),
+            });
+            tokio::spawn(
+                peer.clone()
+                    .run(client.clone(), token.clone(), storage.clone()),
+            );
+            peers.push(peer);
+        }
+
+        Ok(Replicator { peers })
+    }
+
+    /// Queues `hash` for every peer.
+    pub fn enqueue(&self, hash: &str) -> io::Result<()> {
+        for peer in &self.peers {
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(peer.queue_dir.join(hash))?;
+            file.sync_all()?;
+            peer.notify.notify_one();
+        }
+        Ok(())
+    }
+
+    pub fn lag(&self) -> Vec<PeerLag> {
+        self.peers.iter().map(|peer| peer.lag()).collect()
+    }
+}
+
+/// Why a push failed.
+enum PushError {
+    /// The peer is unreachable or cannot take the package right now, such
+    /// as for 5xx answers, 401, 403 and 429
+    Transient(io::Error),
+    /// The peer will never accept the package, e.g. for 400 or 422
+    Rejected(String),
+}
+
+impl From<io::Error> for PushError {
+    fn from(e: io::Error) -> PushError {
+        PushError::Transient(e)
+    }
+}
+
+impl Peer {
+    /// Pending hashes, oldest first.
+    fn pending(&self) -> io::Result<Vec<(String, SystemTime)>> {
+        let mut pending = Vec::new();
+        for entry in fs::read_dir(&self.queue_dir)? {
+            let entry = entry?;
+            if let Ok(hash) = entry.file_name().into_string() {
+                pending.push((hash, entry.metadata()?.modified()?));
+            }
+        }
+        pending.sort_by_key(|(_, queued)| *queued);
+        Ok(pending)
+    }
+
+    fn lag(&self) -> PeerLag {
+        let pending = self.pending().unwrap_or_default();
+        let now = SystemTime::now();
+        let status = self.status.lock().unwrap();
+
+        PeerLag {
+            url: self.url.clone(),
+            pending: pending.len(),
+            rejected: fs::read_dir(&self.rejected_dir).map_or(0, |entries| entries.count()),
+            lag_seconds: pending.first().map_or(0, |(_, queued)| {
+                now.duration_since(*queued).unwrap_or_default().as_secs()
+            }),
+            consecutive_failures: status.consecutive_failures,
+            last_error: status.last_error.clone(),
+            last_success_seconds_ago: status
+                .last_success
+                .map(|t| now.duration_since(t).unwrap_or_default().as_secs()),
+        }
+    }
+
+    async fn push(
+        &self,
+        client: &reqwest::Client,
+        token: Option<&str>,
+        storage: &dyn Storage,
+        hash: &str,
+    ) -> Result<(), PushError> {
+        let Some(stream) = storage.get(hash).await? else {
+            warn!(
+                "{} was removed before it could be replicated to {}",
+                hash, self.url
+            );
+            return Ok(());
+        };
+
+        // reqwest wants a `Sync` body, which storage streams are not
+        let (mut tx, rx) = futures::channel::mpsc::channel(CHANNEL_CAPACITY);
+        let sent = Arc::new(AtomicU64::new(0));
+        tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                let mut stream = stream;
+                while let Some(item) = stream.next().await {
+                    let len = item.as_ref().map_or(0, |chunk| chunk.len() as u64);
+                    if tx.send(item).await.is_err() {
+                        break;
+                    }
+                    sent.fetch_add(len, Ordering::Relaxed);
+                }
+            }
+        });
+
+        let mut request = client
+            .put(format!("{}/cache/{}", self.url, hash))
+            .header(REPLICATED_HEADER, "1")
+            .body(reqwest::Body::wrap_stream(rx));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+
+        // a peer that stops reading or answering is retried like one that is
+        // down
+        let response = request.send();
+        tokio::pin!(response);
+        let mut progress = 0;
+        let response = loop {
+            tokio::select! {
+                response = &mut response => break response.map_err(io::Error::other)?,
+                _ = tokio::time::sleep(STALL_TIMEOUT) => {
+                    let now = sent.load(Ordering::Relaxed);
+                    if now == progress {
+                        return Err(PushError::Transient(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("peer stalled for {:?}", STALL_TIMEOUT),
+                        )));
+                    }
+                    progress = now;
+                }
+            }
+        };
+        let status = response.status();
+        // a conflict means the peer already has the package
+        if status.is_success() || status == StatusCode::CONFLICT {
+            return Ok(());
+        }
+
+        let message = tokio::time::timeout(STALL_TIMEOUT, response.text())
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .unwrap_or_default();
+        let error = format!("peer answered {}: {}", status, message.trim());
+        let retry_later = matches!(
+            status,
+            StatusCode::UNAUTHORIZED
+                | StatusCode::FORBIDDEN
+                | StatusCode::REQUEST_TIMEOUT
+                | StatusCode::TOO_MANY_REQUESTS
+        );
+        if status.is_client_error() && !retry_later {
+            Err(PushError::Rejected(error))
+        } else {
+            Err(PushError::Transient(io::Error::other(error)))
+        }
+    }
+
+    /// Stops retrying `hash`, keeping it in the rejected directory for an
+    /// operator to look at.
+    fn reject(&self, hash: &str) -> io::Result<()> {
+        fs::create_dir_all(&self.rejected_dir)?;
+        fs::rename(self.queue_dir.join(hash), self.rejected_dir.join(hash))
+    }
+
+    async fn run(
+        self: Arc<Self>,
+        client: reqwest::Client,
+        token: Option<String>,
+        storage: Arc<dyn Storage>,
+    ) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let pending = match self.pending() {
+                Ok(pending) => pending,
+                Err(e) => {
+                    warn!("Failed to read replication queue for {}: {}", self.url, e);
+                    Vec::new()
+                }
+            };
+
+            if pending.is_empty() {
+                tokio::select! {
+                    _ = self.notify.notified() => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+                continue;
+            }
+
+            for (hash, _) in pending {
+                match self
+                    .push(&client, token.as_deref(), storage.as_ref(), &hash)
+                    .await
+                {
+                    Ok(()) => {
+                        if let Err(e) = fs::remove_file(self.queue_dir.join(&hash)) {
+                            warn!("Failed to dequeue {} for {}: {}", hash, self.url, e);
+                        }
+                        let mut status = self.status.lock().unwrap();
+                        status.last_success = Some(SystemTime::now());
+                        status.consecutive_failures = 0;
+                        backoff = MIN_BACKOFF;
+
+                        info!("Replicated {} to {}", hash, self.url);
+                    }
+                    Err(PushError::Rejected(e)) => {
+                        warn!("{} rejected {}, not retrying it: {}", self.url, hash, e);
+                        if let Err(e) = self.reject(&hash) {
+                            warn!("Failed to dequeue {} for {}: {}", hash, self.url, e);
+                        }
+                        self.status.lock().unwrap().last_error = Some(e);
+                        // the peer is up, so carry on with the next package
+                    }
+                    Err(PushError::Transient(e)) => {
+                        warn!(
+                            "Failed to replicate {} to {}, retrying in {:?}: {}",
+                            hash, self.url, backoff, e
+                        );
+                        {
+                            let mut status = self.status.lock().unwrap();
+                            status.last_error = Some(e.to_string());
+                            status.consecutive_failures += 1;
+                        }
+
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        // start over from the oldest entry
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
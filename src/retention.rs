@@ -0,0 +1,154 @@
+use std::{
+    io,
+    time::{Duration, SystemTime},
+};
+
+use tracing::info;
+
+use crate::{
+    access::AccessLog,
+    pins::Pins,
+    storage::{ObjectMeta, Storage},
+};
+
+/// Parses a duration such as `30d` or `12h` into seconds (`s`, `m`, `h`, `d`
+/// or `w` suffixes, seconds without one).
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                'w' => 7 * 24 * 60 * 60,
+                _ => return Err(format!("unknown duration suffix '{}'", c)),
+            };
+            (&s[..i], multiplier)
+        }
+        _ => (s, 1),
+    };
+
+    let value: u64 = digits
+        .parse()
+        .map_err(|e| format!("invalid duration '{}': {}", s, e))?;
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("duration '{}' is too long", s))
+}
+
+/// When entries of one root expire.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retention {
+    /// Expire entries not read for this long
+    pub max_idle: Option<Duration>,
+    /// Expire entries uploaded this long ago, even if they are still read
+    pub max_age: Option<Duration>,
+    /// Never expire entries once only this many are left
+    pub min_keep: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Reason {
+    Idle,
+    Age,
+}
+
+impl Reason {
+    pub fn label(self) -> &'static str {
+        match self {
+            Reason::Idle => "idle",
+            Reason::Age => "age",
+        }
+    }
+}
+
+/// The outcome of [`expire`].
+pub struct Expiry {
+    pub remaining: Vec<ObjectMeta>,
+    pub expired: Vec<(ObjectMeta, Reason)>,
+}
+
+impl Retention {
+    pub fn is_set(&self) -> bool {
+        self.max_idle.is_some() || self.max_age.is_some()
+    }
+
+    fn reason(
+        &self,
+        entry: &ObjectMeta,
+        last_access: SystemTime,
+        now: SystemTime,
+    ) -> Option<Reason> {
+        let older_than = |time: SystemTime, max: Option<Duration>| {
+            max.is_some_and(|max| now.duration_since(time).unwrap_or_default() > max)
+        };
+
+        if older_than(entry.modified, self.max_age) {
+            Some(Reason::Age)
+        } else if older_than(last_access, self.max_idle) {
+            Some(Reason::Idle)
+        } else {
+            None
+        }
+    }
+}
+
+/// Deletes the entries that `retention` says have expired, oldest first and
+/// leaving at least `retention.min_keep` entries. Pinned entries never expire.
+///
+/// Entries that were never read since the access log started count as last
+/// read when they were uploaded.
+pub async fn expire(
+    storage: &dyn Storage,
+    mut entries: Vec<ObjectMeta>,
+    retention: &Retention,
+    access_log: &AccessLog,
+    pins: Option<&Pins>,
+) -> io::Result<Expiry> {
+    let mut expired = Vec::new();
+    if !retention.is_set() || entries.len() <= retention.min_keep {
+        return Ok(Expiry {
+            remaining: entries,
+            expired,
+        });
+    }
+
+    let last_access = |e: &ObjectMeta| {
+        access_log
+            .get(&e.key)
+            .map_or(e.modified, |a| a.last_access.max(e.modified))
+    };
+    entries.sort_by_cached_key(|e| last_access(e));
+
+    let now = SystemTime::now();
+    let mut remaining = Vec::with_capacity(entries.len());
+    let mut left = entries.len();
+    for entry in entries {
+        let pinned = pins.is_some_and(|pins| pins.contains(&entry.key));
+        let reason = if left > retention.min_keep && !pinned {
+            retention.reason(&entry, last_access(&entry), now)
+        } else {
+            None
+        };
+        let Some(reason) = reason else {
+            remaining.push(entry);
+            continue;
+        };
+
+        storage.delete(&entry.key).await?;
+        access_log.remove(&entry.key);
+        left -= 1;
+
+        info!(
+            "Expired {} ({}, {})",
+            entry.key,
+            human_bytes::human_bytes(entry.size as f64),
+            reason.label()
+        );
+        expired.push((entry, reason));
+    }
+
+    Ok(Expiry { remaining, expired })
+}
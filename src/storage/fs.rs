@@ -0,0 +1,306 @@
+use std::{
+    ffi::OsString,
+    fs, io,
+    io::SeekFrom,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{info, warn};
+
+use super::{ByteStream, ContentHasher, Layout, ObjectMeta, Storage, Validate};
+
+/// Extension given to in-progress uploads; anything with it is never served.
+const STAGING_EXTENSION: &str = "partial";
+
+/// Stores entries as files under a local directory.
+pub struct FsStorage {
+    root: PathBuf,
+    layout: Layout,
+}
+
+impl FsStorage {
+    pub fn new(root: PathBuf, layout: Layout) -> FsStorage {
+        FsStorage { root, layout }
+    }
+
+    pub fn path_for(&self, key: &str) -> PathBuf {
+        self.root.join(self.layout.relative_path(key))
+    }
+
+    /// Removes leftover staging files (from a crash or kill mid-upload) that
+    /// were last written at least `min_age` ago.
+    ///
+    /// Staging files live next to their final path, so this looks at the root
+    /// and its direct subdirectories.
+    pub fn sweep_staging_files(&self, min_age: Duration) -> io::Result<()> {
+        fn sweep_dir(dir: &Path, recurse: bool, min_age: Duration) -> io::Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                let file_type = entry.file_type()?;
+
+                if file_type.is_dir()
+                    && recurse
+                    && !entry.file_name().to_string_lossy().starts_with('.')
+                {
+                    sweep_dir(&path, false, min_age)?;
+                } else if file_type.is_file()
+                    && path.extension() == Some(STAGING_EXTENSION.as_ref())
+                    && entry.metadata()?.modified()?.elapsed().unwrap_or_default() >= min_age
+                {
+                    info!("Removing leftover staging file {}", path.display());
+                    fs::remove_file(&path)?;
+                }
+            }
+            Ok(())
+        }
+
+        if !self.root.exists() {
+            return Ok(());
+        }
+        sweep_dir(&self.root, true, min_age)
+    }
+
+    /// Lists every committed entry, skipping staging files and our own
+    /// bookkeeping files.
+    fn list_blocking(&self) -> io::Result<Vec<ObjectMeta>> {
+        fn visit(
+            dir: &Path,
+            layout: Layout,
+            recurse: bool,
+            entries: &mut Vec<ObjectMeta>,
+        ) -> io::Result<()> {
+            for dir_entry in fs::read_dir(dir)? {
+                let dir_entry = dir_entry?;
+                let file_type = dir_entry.file_type()?;
+
+                if file_type.is_dir() {
+                    // our own bookkeeping directories are hidden
+                    if recurse && !dir_entry.file_name().to_string_lossy().starts_with('.') {
+                        visit(&dir_entry.path(), layout, false, entries)?;
+                    }
+                    continue;
+                }
+
+                let name = dir_entry.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                if !file_type.is_file()
+                    || name.starts_with('.')
+                    || name.ends_with(STAGING_EXTENSION)
+                {
+                    continue;
+                }
+                let Some(key) = layout.key_from_file_name(name) else {
+                    continue;
+                };
+
+                let metadata = dir_entry.metadata()?;
+                entries.push(ObjectMeta {
+                    key: key.to_owned(),
+                    size: metadata.len(),
+                    modified: metadata.modified()?,
+                });
+            }
+            Ok(())
+        }
+
+        let mut entries = Vec::new();
+        if self.root.exists() {
+            visit(&self.root, self.layout, true, &mut entries)?;
+        }
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl Storage for FsStorage {
+    async fn get(&self, key: &str) -> io::Result<Option<ByteStream>> {
+        match File::open(self.path_for(key)).await {
+            Ok(file) => Ok(Some(ReaderStream::new(file).boxed())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_range(&self, key: &str, range: Range<u64>) -> io::Result<Option<ByteStream>> {
+        let mut file = match File::open(self.path_for(key)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        file.seek(SeekFrom::Start(range.start)).await?;
+        Ok(Some(
+            ReaderStream::new(file.take(range.end - range.start)).boxed(),
+        ))
+    }
+
+    async fn head(&self, key: &str) -> io::Result<Option<ObjectMeta>> {
+        match tokio::fs::metadata(self.path_for(key)).await {
+            Ok(metadata) => Ok(Some(ObjectMeta {
+                key: key.to_owned(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        stream: ByteStream,
+        expected_digest: Option<&str>,
+        validate: Option<Validate<'_>>,
+    ) -> io::Result<u64> {
+        let path = self.path_for(key);
+        let dir = path.parent().unwrap();
+        if !tokio::fs::try_exists(dir).await? {
+            tokio::fs::create_dir_all(dir).await?;
+            // a new prefix directory is only durable once its parent is synced
+            if let Some(parent) = dir.parent() {
+                sync_dir(parent).await?;
+            }
+        }
+
+        write_stream_to_file(&path, stream, expected_digest, validate).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<bool> {
+        match tokio::fs::remove_file(self.path_for(key)).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<ObjectMeta>> {
+        tokio::task::block_in_place(|| self.list_blocking())
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path_for(key))
+    }
+}
+
+/// A file that is being written and is deleted on drop unless it was committed.
+///
+/// Dropping covers both upload errors and the handler future being dropped
+/// because the client went away.
+struct StagingFile {
+    path: PathBuf,
+    committed: bool,
+}
+
+impl StagingFile {
+    fn for_destination(dest: &Path) -> StagingFile {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let mut name = dest.file_name().map(OsString::from).unwrap_or_default();
+        name.push(format!(
+            ".{}-{}.{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            STAGING_EXTENSION
+        ));
+
+        StagingFile {
+            path: dest.with_file_name(name),
+            committed: false,
+        }
+    }
+
+    /// Renames the staging file to `dest`, then syncs the directory so the
+    /// entry survives a crash once the upload is acknowledged.
+    async fn commit(mut self, dest: &Path) -> io::Result<()> {
+        tokio::fs::rename(&self.path, dest).await?;
+        self.committed = true;
+        if let Some(dir) = dest.parent() {
+            sync_dir(dir).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StagingFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            if let Err(e) = fs::remove_file(&self.path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!(
+                        "Failed to remove staging file {}: {}",
+                        self.path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Makes renames and new entries in `dir` durable. Directories cannot be
+/// synced like this on Windows, where it is not needed.
+async fn sync_dir(dir: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Writes `stream` to `path` atomically.
+///
+/// The body goes to a staging file in the same directory which is fsynced and
+/// renamed over `path` only once the whole stream was received, so readers
+/// never see a partial upload. `validate` checks the staging file in place,
+/// so even huge packages are only written once.
+async fn write_stream_to_file(
+    path: &Path,
+    stream: ByteStream,
+    expected_digest: Option<&str>,
+    validate: Option<Validate<'_>>,
+) -> io::Result<u64> {
+    let staging = StagingFile::for_destination(path);
+    let mut file = BufWriter::new(File::create(&staging.path).await?);
+
+    let mut hasher = expected_digest.map(ContentHasher::for_key);
+
+    let bytes = {
+        let body = stream.inspect_ok(|chunk| {
+            if let Some(hasher) = &mut hasher {
+                hasher.update(chunk);
+            }
+        });
+        let body_reader = StreamReader::new(body);
+        futures::pin_mut!(body_reader);
+
+        tokio::io::copy(&mut body_reader, &mut file).await?
+    };
+
+    if let (Some(hasher), Some(expected)) = (hasher, expected_digest) {
+        hasher.verify(expected)?;
+    }
+
+    file.flush().await?;
+    if let Some(validate) = validate {
+        let mut staged = fs::File::open(&staging.path)?;
+        tokio::task::block_in_place(|| validate(&mut staged))?;
+    }
+    file.get_ref().sync_all().await?;
+    drop(file);
+
+    staging.commit(path).await?;
+
+    Ok(bytes)
+}
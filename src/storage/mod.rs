@@ -0,0 +1,355 @@
+use std::{
+    fs::File,
+    io::{self, Seek},
+    ops::Range,
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use sha2::{Digest, Sha256, Sha512};
+use tokio_util::io::StreamReader;
+
+mod fs;
+mod s3;
+
+pub use fs::FsStorage;
+pub use s3::S3Storage;
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// Checks a complete upload before [`Storage::put`] makes it visible, such
+/// as whether it is a well-formed package. The file is positioned at the
+/// start.
+pub type Validate<'a> = &'a mut (dyn FnMut(&mut File) -> io::Result<()> + Send);
+
+/// Whether `key` is a lowercase hex SHA-256 or SHA-512 digest, the only keys
+/// ever stored.
+pub fn is_valid_key(key: &str) -> bool {
+    (key.len() == 64 || key.len() == 128)
+        && key.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// How keys map to locations inside a storage root.
+#[derive(Clone, Copy, Debug)]
+pub enum Layout {
+    /// `<key[..2]>/<key>.zip`, the layout vcpkg binary packages are stored in.
+    Binary,
+    /// `<key>`, used for assets.
+    Asset,
+}
+
+impl Layout {
+    /// Returns the location of `key` relative to the root, `/`-separated.
+    ///
+    /// `key` must already have been validated for the path to stay inside the
+    /// root; others still get some path rather than a panic.
+    pub fn relative_path(self, key: &str) -> String {
+        match self {
+            Layout::Binary => format!("{}/{}.zip", key.get(..2).unwrap_or("__"), key),
+            Layout::Asset => key.to_owned(),
+        }
+    }
+
+    /// Inverse of [`Layout::relative_path`] for a single file name; returns
+    /// `None` for anything that is not an entry, including files named after
+    /// something other than a valid key.
+    pub fn key_from_file_name(self, name: &str) -> Option<&str> {
+        match self {
+            Layout::Binary => name.strip_suffix(".zip"),
+            Layout::Asset => Some(name),
+        }
+        .filter(|key| is_valid_key(key))
+    }
+}
+
+/// Size and modification time of a stored entry.
+#[derive(Clone, Debug)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Where cache entries are kept.
+///
+/// Keys passed in are always validated by the handlers first, so
+/// implementations can use them in paths as they are.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Opens `key` for reading, or returns `None` if it does not exist.
+    async fn get(&self, key: &str) -> io::Result<Option<ByteStream>>;
+
+    /// Opens the bytes of `key` in `range`, or returns `None` if it does not
+    /// exist. `range` must lie within the entry.
+    async fn get_range(&self, key: &str, range: Range<u64>) -> io::Result<Option<ByteStream>>;
+
+    async fn head(&self, key: &str) -> io::Result<Option<ObjectMeta>>;
+
+    /// Stores `stream` under `key`, returning the number of bytes written.
+    ///
+    /// The entry only becomes visible once the whole stream was received. If
+    /// `expected_digest` is given, the body is hashed while it is copied and
+    /// the upload fails with [`io::ErrorKind::InvalidData`] without committing
+    /// anything if the digest differs. Likewise, nothing is committed if
+    /// `validate` fails.
+    async fn put(
+        &self,
+        key: &str,
+        stream: ByteStream,
+        expected_digest: Option<&str>,
+        validate: Option<Validate<'_>>,
+    ) -> io::Result<u64>;
+
+    /// Deletes `key`, returning whether it existed.
+    async fn delete(&self, key: &str) -> io::Result<bool>;
+
+    async fn list(&self) -> io::Result<Vec<ObjectMeta>>;
+
+    /// Returns where `key` is stored if it is a local file, so it can be read
+    /// directly instead of through [`Storage::get`].
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Opens `key` as a local file, downloading it to a temporary file first if
+/// the storage is not local.
+pub async fn open_local(storage: &dyn Storage, key: &str) -> io::Result<Option<File>> {
+    if let Some(path) = storage.local_path(key) {
+        return match File::open(path) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+    }
+
+    let Some(stream) = storage.get(key).await? else {
+        return Ok(None);
+    };
+    let mut file = tokio::fs::File::from_std(tempfile::tempfile()?);
+    tokio::io::copy(&mut StreamReader::new(stream), &mut file).await?;
+
+    let mut file = file.into_std().await;
+    file.rewind()?;
+    Ok(Some(file))
+}
+
+/// Hashes an upload so it can be checked against the key it is stored under.
+pub(crate) enum ContentHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl ContentHasher {
+    /// Picks the digest matching an already validated key by its length.
+    pub fn for_key(key: &str) -> ContentHasher {
+        if key.len() == 64 {
+            ContentHasher::Sha256(Sha256::new())
+        } else {
+            ContentHasher::Sha512(Sha512::new())
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            ContentHasher::Sha256(h) => h.update(data),
+            ContentHasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    /// Fails with [`io::ErrorKind::InvalidData`] if the digest differs from
+    /// `expected`.
+    pub fn verify(self, expected: &str) -> io::Result<()> {
+        let actual = match self {
+            ContentHasher::Sha256(h) => format!("{:x}", h.finalize()),
+            ContentHasher::Sha512(h) => format!("{:x}", h.finalize()),
+        };
+
+        if actual != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("content digest {} does not match key {}", actual, expected),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        path::{Component, Path},
+        sync::Arc,
+    };
+
+    use futures::{StreamExt, TryStreamExt};
+    use object_store::{memory::InMemory, path::Path as ObjectPath, ObjectStore};
+
+    use super::*;
+
+    const SHA256: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    /// SHA-256 of `hello world`
+    const HELLO_DIGEST: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    /// Valid keys must resolve to plain names under the root.
+    fn assert_stays_in_root(relative: &str) {
+        assert!(Path::new(relative)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_))));
+    }
+
+    #[test]
+    fn binary_layout() {
+        let sha512 = SHA256.repeat(2);
+        for key in [SHA256, sha512.as_str()] {
+            let relative = Layout::Binary.relative_path(key);
+            assert_eq!(relative, format!("01/{}.zip", key));
+            assert_stays_in_root(&relative);
+            assert_eq!(
+                Layout::Binary.key_from_file_name(&format!("{}.zip", key)),
+                Some(key)
+            );
+        }
+    }
+
+    #[test]
+    fn asset_layout() {
+        let sha512 = SHA256.repeat(2);
+        for key in [SHA256, sha512.as_str()] {
+            let relative = Layout::Asset.relative_path(key);
+            assert_eq!(relative, key);
+            assert_stays_in_root(&relative);
+            assert_eq!(Layout::Asset.key_from_file_name(key), Some(key));
+        }
+    }
+
+    #[test]
+    fn invalid_file_names_are_not_keys() {
+        for layout in [Layout::Binary, Layout::Asset] {
+            for name in JUNK {
+                let name = name.rsplit('/').next().unwrap();
+                assert_eq!(layout.key_from_file_name(name), None, "{}", name);
+            }
+            // must not panic either
+            for key in ["", "a", "é"] {
+                layout.relative_path(key);
+            }
+        }
+    }
+
+    fn hello_world() -> ByteStream {
+        futures::stream::iter([Ok(Bytes::from("hello ")), Ok(Bytes::from("world"))]).boxed()
+    }
+
+    async fn read(stream: io::Result<Option<ByteStream>>) -> Option<Vec<u8>> {
+        let stream = stream.unwrap()?;
+        Some(
+            stream
+                .try_fold(Vec::new(), |mut data, chunk| async move {
+                    data.extend_from_slice(&chunk);
+                    Ok(data)
+                })
+                .await
+                .unwrap(),
+        )
+    }
+
+    /// Files that are not entries, relative to the root of either layout.
+    const JUNK: [&str; 4] = ["a", "ab/a.zip", "not-a-digest", "01/not-a-digest.zip"];
+
+    /// What every backend must do, in either layout. The root must already
+    /// hold the [`JUNK`] files, which are never listed.
+    async fn check_storage(storage: &dyn Storage) {
+        let key = HELLO_DIGEST;
+        assert!(storage.head(key).await.unwrap().is_none());
+        assert!(read(storage.get(key).await).await.is_none());
+        assert!(!storage.delete(key).await.unwrap());
+
+        let written = storage
+            .put(key, hello_world(), Some(key), None)
+            .await
+            .unwrap();
+        assert_eq!(written, 11);
+        assert_eq!(storage.head(key).await.unwrap().unwrap().size, 11);
+        assert_eq!(read(storage.get(key).await).await.unwrap(), b"hello world");
+        assert_eq!(
+            read(storage.get_range(key, 6..11).await).await.unwrap(),
+            b"world"
+        );
+
+        // a digest mismatch must not leave anything behind
+        let err = storage
+            .put(SHA256, hello_world(), Some(SHA256), None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(storage.head(SHA256).await.unwrap().is_none());
+
+        // so must a failed validation, which sees the whole upload
+        let mut validate = |file: &mut File| {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            assert_eq!(contents, "hello world");
+            Err(io::Error::new(io::ErrorKind::InvalidData, "not a package"))
+        };
+        let err = storage
+            .put(SHA256, hello_world(), None, Some(&mut validate))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(storage.head(SHA256).await.unwrap().is_none());
+
+        let listed = storage.list().await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].key, key);
+        assert_eq!(listed[0].size, 11);
+
+        assert!(storage.delete(key).await.unwrap());
+        assert!(storage.head(key).await.unwrap().is_none());
+        assert!(storage.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fs_storage() {
+        for layout in [Layout::Binary, Layout::Asset] {
+            let root = tempfile::tempdir().unwrap();
+            for junk in JUNK {
+                let path = root.path().join(junk);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, "junk").unwrap();
+            }
+            check_storage(&FsStorage::new(root.path().to_owned(), layout)).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn s3_storage() {
+        for layout in [Layout::Binary, Layout::Asset] {
+            let store = Arc::new(InMemory::new());
+            for junk in JUNK {
+                let path = ObjectPath::from(format!("binary/{}", junk));
+                store.put(&path, "junk".into()).await.unwrap();
+            }
+            check_storage(&S3Storage::with_store(store, "binary", layout)).await;
+        }
+    }
+
+    /// Runs against a real S3-compatible service, e.g. MinIO, named by
+    /// `VCPKG_CACHE_TEST_S3_ENDPOINT` and `VCPKG_CACHE_TEST_S3_BUCKET`, with
+    /// credentials in the `AWS_*` variables.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn s3_storage_against_endpoint() {
+        let endpoint = std::env::var("VCPKG_CACHE_TEST_S3_ENDPOINT").unwrap();
+        let bucket = std::env::var("VCPKG_CACHE_TEST_S3_BUCKET").unwrap();
+        let prefix = format!("test-{}", std::process::id());
+        for layout in [Layout::Binary, Layout::Asset] {
+            let storage = S3Storage::new(&bucket, Some(&endpoint), &prefix, layout).unwrap();
+            check_storage(&storage).await;
+        }
+    }
+}
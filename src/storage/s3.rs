@@ -0,0 +1,215 @@
+use std::{
+    io::{self, Seek},
+    ops::Range,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{
+    aws::AmazonS3Builder, path::Path as ObjectPath, GetOptions, GetRange, ObjectStore,
+    WriteMultipart,
+};
+
+use tokio::io::AsyncWriteExt;
+
+use super::{ByteStream, ContentHasher, Layout, ObjectMeta, Storage, Validate};
+
+/// Upload parts that may be in flight at once for a single upload.
+const MAX_CONCURRENT_PARTS: usize = 4;
+
+/// Stores entries as objects in an S3-compatible bucket.
+///
+/// Several servers can share one bucket, since uploads only become visible
+/// once the multipart upload is completed. Uploads abandoned because the
+/// server died are left for the bucket's lifecycle rules to clean up.
+pub struct S3Storage {
+    store: Arc<dyn ObjectStore>,
+    prefix: String,
+    layout: Layout,
+}
+
+impl S3Storage {
+    /// Connects to `bucket`, keeping entries under `prefix`.
+    ///
+    /// Credentials and region are taken from the usual `AWS_*` environment
+    /// variables. `endpoint` points at non-AWS services such as MinIO.
+    pub fn new(
+        bucket: &str,
+        endpoint: Option<&str>,
+        prefix: &str,
+        layout: Layout,
+    ) -> io::Result<S3Storage> {
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+        if let Some(endpoint) = endpoint {
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+
+        let store = builder.build().map_err(object_error)?;
+        Ok(S3Storage::with_store(Arc::new(store), prefix, layout))
+    }
+
+    /// Keeps entries under `prefix` in any object store, such as the
+    /// in-memory one the tests use.
+    pub fn with_store(store: Arc<dyn ObjectStore>, prefix: &str, layout: Layout) -> S3Storage {
+        S3Storage {
+            store,
+            prefix: prefix.to_owned(),
+            layout,
+        }
+    }
+
+    fn object_path(&self, key: &str) -> ObjectPath {
+        ObjectPath::from(format!(
+            "{}/{}",
+            self.prefix,
+            self.layout.relative_path(key)
+        ))
+    }
+}
+
+fn object_error(e: object_store::Error) -> io::Error {
+    match e {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, e),
+        e => io::Error::other(e),
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn get(&self, key: &str) -> io::Result<Option<ByteStream>> {
+        match self.store.get(&self.object_path(key)).await {
+            Ok(result) => Ok(Some(result.into_stream().map_err(object_error).boxed())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(object_error(e)),
+        }
+    }
+
+    async fn get_range(&self, key: &str, range: Range<u64>) -> io::Result<Option<ByteStream>> {
+        let options = GetOptions {
+            range: Some(GetRange::Bounded(range)),
+            ..GetOptions::default()
+        };
+        match self.store.get_opts(&self.object_path(key), options).await {
+            Ok(result) => Ok(Some(result.into_stream().map_err(object_error).boxed())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(object_error(e)),
+        }
+    }
+
+    async fn head(&self, key: &str) -> io::Result<Option<ObjectMeta>> {
+        match self.store.head(&self.object_path(key)).await {
+            Ok(meta) => Ok(Some(ObjectMeta {
+                key: key.to_owned(),
+                size: meta.size,
+                modified: meta.last_modified.into(),
+            })),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(object_error(e)),
+        }
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        mut stream: ByteStream,
+        expected_digest: Option<&str>,
+        validate: Option<Validate<'_>>,
+    ) -> io::Result<u64> {
+        let upload = self
+            .store
+            .put_multipart(&self.object_path(key))
+            .await
+            .map_err(object_error)?;
+        let mut writer = WriteMultipart::new(upload);
+
+        let mut hasher = expected_digest.map(ContentHasher::for_key);
+
+        let copied: io::Result<u64> = async {
+            // objects cannot be checked in place, so validated uploads are
+            // also copied to a local file
+            let mut spool = match validate {
+                Some(_) => Some(tokio::fs::File::from_std(tempfile::tempfile()?)),
+                None => None,
+            };
+
+            let mut bytes = 0;
+            while let Some(chunk) = stream.try_next().await? {
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(&chunk);
+                }
+                if let Some(spool) = &mut spool {
+                    spool.write_all(&chunk).await?;
+                }
+                writer
+                    .wait_for_capacity(MAX_CONCURRENT_PARTS)
+                    .await
+                    .map_err(object_error)?;
+                writer.write(&chunk);
+                bytes += chunk.len() as u64;
+            }
+
+            if let (Some(hasher), Some(expected)) = (hasher, expected_digest) {
+                hasher.verify(expected)?;
+            }
+            if let (Some(spool), Some(validate)) = (spool, validate) {
+                let mut spool = spool.into_std().await;
+                tokio::task::block_in_place(|| {
+                    spool.rewind()?;
+                    validate(&mut spool)
+                })?;
+            }
+            Ok(bytes)
+        }
+        .await;
+
+        match copied {
+            Ok(bytes) => {
+                writer.finish().await.map_err(object_error)?;
+                Ok(bytes)
+            }
+            Err(e) => {
+                // the upload was never completed so nothing is visible, this
+                // only frees the parts already sent
+                let _ = writer.abort().await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<bool> {
+        let path = self.object_path(key);
+
+        // S3 deletes succeed for missing objects, so check first to report it
+        match self.store.head(&path).await {
+            Ok(_) => {}
+            Err(object_store::Error::NotFound { .. }) => return Ok(false),
+            Err(e) => return Err(object_error(e)),
+        }
+        self.store.delete(&path).await.map_err(object_error)?;
+        Ok(true)
+    }
+
+    async fn list(&self) -> io::Result<Vec<ObjectMeta>> {
+        let prefix = ObjectPath::from(self.prefix.as_str());
+        let layout = self.layout;
+
+        self.store
+            .list(Some(&prefix))
+            .map_err(object_error)
+            .try_filter_map(|meta| async move {
+                let Some(name) = meta.location.filename() else {
+                    return Ok(None);
+                };
+                Ok(layout.key_from_file_name(name).map(|key| ObjectMeta {
+                    key: key.to_owned(),
+                    size: meta.size,
+                    modified: meta.last_modified.into(),
+                }))
+            })
+            .try_collect()
+            .await
+    }
+}
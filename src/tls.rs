@@ -0,0 +1,30 @@
+use std::path::PathBuf;
+
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+/// Reloads the certificate and key from disk whenever the process gets
+/// `SIGHUP`, so rotated certificates are picked up without a restart.
+///
+/// If the new files cannot be loaded, the previous certificate stays in use.
+pub async fn reload_on_sighup(tls: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP, TLS reload is disabled: {}", e);
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        match tls.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => info!("Reloaded TLS certificate from {}", cert.display()),
+            Err(e) => warn!(
+                "Failed to reload TLS certificate from {}, keeping the old one: {}",
+                cert.display(),
+                e
+            ),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    http::StatusCode,
+    response::{Html, IntoResponse},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{namespace::CurrentNamespace, storage_error};
+
+/// The dashboard is a single static page that loads everything else from
+/// [`entries_get`], so it can send the user's token along. Its URLs are
+/// relative, so the same page serves every namespace.
+pub async fn page_get() -> impl IntoResponse {
+    Html(include_str!("ui.html"))
+}
+
+/// A binary package as shown on the dashboard.
+#[derive(Serialize)]
+pub struct Entry {
+    hash: String,
+    port: Option<String>,
+    version: Option<String>,
+    triplet: Option<String>,
+    size: u64,
+    uploaded: u64,
+    last_access: Option<u64>,
+    hits: u64,
+    pinned: bool,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Lists every binary package with what the index and access log know about
+/// it, most recently uploaded first.
+pub async fn entries_get(
+    CurrentNamespace(namespace): CurrentNamespace,
+) -> Result<Json<Vec<Entry>>, (StatusCode, String)> {
+    let objects = namespace.binary.list().await.map_err(storage_error)?;
+    let mut packages: HashMap<_, _> =
+        tokio::task::block_in_place(|| namespace.index.query(None, None))
+            .map_err(storage_error)?
+            .into_iter()
+            .map(|package| (package.abi.clone(), package))
+            .collect();
+
+    let mut entries: Vec<Entry> = objects
+        .into_iter()
+        .map(|object| {
+            let package = packages.remove(&object.key);
+            let access = namespace.binary_access.get(&object.key);
+            Entry {
+                port: package.as_ref().map(|p| p.port.clone()),
+                version: package.as_ref().and_then(|p| p.version.clone()),
+                triplet: package.and_then(|p| p.triplet),
+                size: object.size,
+                uploaded: unix_secs(object.modified),
+                last_access: access.map(|a| unix_secs(a.last_access)),
+                hits: access.map_or(0, |a| a.hits),
+                pinned: namespace.pins.contains(&object.key),
+                hash: object.key,
+            }
+        })
+        .collect();
+    entries.sort_by_key(|e| Reverse(e.uploaded));
+
+    Ok(Json(entries))
+}
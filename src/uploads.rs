@@ -0,0 +1,37 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use crate::namespace::Cache;
+
+/// Keys that are currently being uploaded, so concurrent uploads of the same
+/// key can be turned away instead of racing each other.
+#[derive(Default)]
+pub struct Uploads {
+    in_flight: Mutex<HashSet<(Cache, String)>>,
+}
+
+/// Marks an upload as in flight until dropped.
+pub struct UploadGuard<'a> {
+    uploads: &'a Uploads,
+    entry: (Cache, String),
+}
+
+impl Uploads {
+    /// Claims `key` in `cache`, or returns `None` if another upload of it is
+    /// already in flight.
+    pub fn begin(&self, cache: Cache, key: &str) -> Option<UploadGuard<'_>> {
+        let entry = (cache, key.to_owned());
+        if !self.in_flight.lock().unwrap().insert(entry.clone()) {
+            return None;
+        }
+        Some(UploadGuard {
+            uploads: self,
+            entry,
+        })
+    }
+}
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        self.uploads.in_flight.lock().unwrap().remove(&self.entry);
+    }
+}
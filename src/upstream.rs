@@ -0,0 +1,188 @@
+use std::{io, sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use tracing::{info, warn};
+
+use crate::{
+    commit_package,
+    metrics::Metrics,
+    namespace::{Cache, Namespace},
+    storage::ByteStream,
+};
+
+/// Chunks buffered between the upstream download and the client or storage.
+const CHANNEL_CAPACITY: usize = 16;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the upstream may take to answer, and to send each chunk of a
+/// download. Downloads as a whole may take as long as they need.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Another instance of this server (or anything speaking the same
+/// `/cache/:hash` protocol) that local misses are fetched from.
+pub struct Upstream {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl Upstream {
+    pub fn new(url: &str, token: Option<String>) -> Upstream {
+        Upstream {
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()
+                .expect("TLS backend can be initialized"),
+            url: url.trim_end_matches('/').to_owned(),
+            token,
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, hash: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}/cache/{}", self.url, hash));
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Returns whether the upstream has `hash`.
+    pub async fn head(&self, hash: &str) -> io::Result<bool> {
+        let response = self
+            .request(reqwest::Method::HEAD, hash)
+            .timeout(RESPONSE_TIMEOUT)
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => Err(io::Error::other(format!(
+                "upstream answered HEAD {} with {}",
+                hash, status
+            ))),
+        }
+    }
+
+    /// Starts downloading `hash`, or returns `None` if the upstream does not
+    /// have it either.
+    pub async fn get(&self, hash: &str) -> io::Result<Option<ByteStream>> {
+        let response = tokio::time::timeout(
+            RESPONSE_TIMEOUT,
+            self.request(reqwest::Method::GET, hash).send(),
+        )
+        .await
+        .map_err(|_| timed_out(hash))?
+        .map_err(io::Error::other)?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(fail_when_stalled(
+                response.bytes_stream().map_err(io::Error::other).boxed(),
+                hash.to_owned(),
+            ))),
+            status => Err(io::Error::other(format!(
+                "upstream answered GET {} with {}",
+                hash, status
+            ))),
+        }
+    }
+}
+
+fn timed_out(hash: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("upstream stopped responding to GET {}", hash),
+    )
+}
+
+/// Ends the download of `hash` with an error once no chunk arrived for
+/// [`RESPONSE_TIMEOUT`], instead of waiting forever.
+fn fail_when_stalled(body: ByteStream, hash: String) -> ByteStream {
+    futures::stream::unfold(Some(body), move |body| {
+        let hash = hash.clone();
+        async move {
+            let mut body = body?;
+            match tokio::time::timeout(RESPONSE_TIMEOUT, body.next()).await {
+                Ok(item) => item.map(|item| (item, Some(body))),
+                Err(_) => Some((Err(timed_out(&hash)), None)),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Streams `download` to the returned stream while also storing it under
+/// `hash` in `namespace`, checked and indexed like an upload.
+///
+/// The download is driven by a background task, so it is stored completely
+/// even if the client goes away halfway through. A failed download fails the
+/// storage upload too, so nothing partial is committed. The client still gets
+/// the whole package if it is not stored, e.g. because another upload of it is
+/// in flight or the quota is used up.
+pub fn pull_through(
+    namespace: Arc<Namespace>,
+    metrics: Arc<Metrics>,
+    hash: String,
+    mut download: ByteStream,
+) -> ByteStream {
+    let (mut client_tx, client_rx) = futures::channel::mpsc::channel(CHANNEL_CAPACITY);
+    let (mut storage_tx, storage_rx) = futures::channel::mpsc::channel(CHANNEL_CAPACITY);
+
+    let store = {
+        let hash = hash.clone();
+        tokio::spawn(async move {
+            let Some(_upload) = namespace.uploads.begin(Cache::Binary, &hash) else {
+                return Ok(None);
+            };
+            commit_package(&namespace, &metrics, &hash, storage_rx.boxed(), None)
+                .await
+                .map(Some)
+        })
+    };
+
+    tokio::spawn(async move {
+        let mut client_connected = true;
+        let mut storing = true;
+        while let Some(item) = download.next().await {
+            let failed = item.is_err();
+
+            if client_connected {
+                let forwarded = match &item {
+                    Ok(chunk) => Ok(chunk.clone()),
+                    Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                };
+                client_connected = client_tx.send(forwarded).await.is_ok();
+            }
+            if storing {
+                storing = storage_tx.send(item).await.is_ok();
+            }
+
+            if failed || !(client_connected || storing) {
+                break;
+            }
+        }
+        // closing the channel is what tells storage the download finished
+        drop(storage_tx);
+
+        match store.await {
+            Ok(Ok(Some(bytes))) => info!(
+                "Pulled {} ({}) from upstream",
+                hash,
+                human_bytes::human_bytes(bytes as f64)
+            ),
+            Ok(Ok(None)) => info!(
+                "Not storing {} pulled from upstream, it is already being uploaded",
+                hash
+            ),
+            Ok(Err((_, e))) => warn!("Failed to store {} pulled from upstream: {}", hash, e),
+            Err(e) => warn!("Storing {} pulled from upstream panicked: {}", hash, e),
+        }
+    });
+
+    client_rx.boxed()
+}